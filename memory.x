@@ -0,0 +1,8 @@
+MEMORY
+{
+  /* The STM32F446RE has 512K of flash. Its last two 128K sectors, from 0x08040000, hold the
+     calibrations (see src/main.rs) and are erased when they are saved, so the firmware must not
+     be linked there. */
+  FLASH : ORIGIN = 0x08000000, LENGTH = 256K
+  RAM : ORIGIN = 0x20000000, LENGTH = 128K
+}
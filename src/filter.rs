@@ -0,0 +1,298 @@
+use crate::reading::{DistanceReading, RangeQuality};
+use heapless::Vec;
+
+/// Number of past readings a filter stage can look at
+pub const HISTORY_CAPACITY: usize = 15;
+/// Maximum number of stages in a [`FilterChain`]
+pub const MAX_STAGES: usize = 4;
+
+/// Fixed-size ring buffer keeping the most recent values
+#[derive(Debug, Clone)]
+pub struct History {
+    values: [f32; HISTORY_CAPACITY],
+    /// Index the next value is written at
+    next: usize,
+    len: usize,
+}
+
+impl History {
+    pub const fn new() -> Self {
+        Self {
+            values: [0.0; HISTORY_CAPACITY],
+            next: 0,
+            len: 0,
+        }
+    }
+
+    /// Adds a value, overwriting the oldest one if the buffer is full
+    pub fn push(&mut self, value: f32) {
+        self.values[self.next] = value;
+        self.next = (self.next + 1) % HISTORY_CAPACITY;
+        self.len = (self.len + 1).min(HISTORY_CAPACITY);
+    }
+
+    /// Iterates over the `count` most recent values, newest first
+    pub fn recent(&self, count: usize) -> impl Iterator<Item = f32> + '_ {
+        (1..=count.min(self.len))
+            .map(move |age| self.values[(self.next + HISTORY_CAPACITY - age) % HISTORY_CAPACITY])
+    }
+
+    /// Median of the `count` most recent values, `None` if the buffer is empty
+    pub fn median(&self, count: usize) -> Option<f32> {
+        let mut window = [0.0; HISTORY_CAPACITY];
+        let mut len = 0;
+        for value in self.recent(count) {
+            window[len] = value;
+            len += 1;
+        }
+        let window = &mut window[..len];
+        window.sort_unstable_by(|a, b| a.total_cmp(b));
+        match len {
+            0 => None,
+            _ if len % 2 == 1 => Some(window[len / 2]),
+            _ => Some((window[len / 2 - 1] + window[len / 2]) / 2.0),
+        }
+    }
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// One stage of a [`FilterChain`], with its own history
+#[derive(Debug, Clone)]
+pub enum FilterStage {
+    /// Replaces the distance by the median of the last `window` ones
+    Median { window: usize, history: History },
+    /// Exponential moving average, `alpha` is the weight of the new reading
+    Ema { alpha: f32, average: Option<f32> },
+    /// Marks as [`RangeQuality::Invalid`] the readings further than `max_sigmas` times their
+    /// sigma from the median of the last `window` readings. Readings without a sigma (VL53L0X)
+    /// pass through.
+    ///
+    /// Rejected readings still go in the history, so that a real change of distance is accepted
+    /// once it makes up half of the window.
+    RejectOutliers {
+        max_sigmas: f32,
+        window: usize,
+        history: History,
+    },
+}
+
+impl FilterStage {
+    pub fn median(window: usize) -> Self {
+        FilterStage::Median {
+            window: window.clamp(1, HISTORY_CAPACITY),
+            history: History::new(),
+        }
+    }
+
+    pub fn ema(alpha: f32) -> Self {
+        FilterStage::Ema {
+            alpha: alpha.clamp(0.0, 1.0),
+            average: None,
+        }
+    }
+
+    pub fn reject_outliers(max_sigmas: f32, window: usize) -> Self {
+        FilterStage::RejectOutliers {
+            max_sigmas,
+            window: window.clamp(1, HISTORY_CAPACITY),
+            history: History::new(),
+        }
+    }
+
+    /// Forgets the past readings
+    fn reset(&mut self) {
+        match self {
+            FilterStage::Median { history, .. } | FilterStage::RejectOutliers { history, .. } => {
+                *history = History::new();
+            }
+            FilterStage::Ema { average, .. } => *average = None,
+        }
+    }
+
+    fn apply(&mut self, mut reading: DistanceReading) -> DistanceReading {
+        let mm = reading.mm as f32;
+        match self {
+            FilterStage::Median { window, history } => {
+                history.push(mm);
+                if let Some(median) = history.median(*window) {
+                    reading.mm = median as u16;
+                }
+            }
+            FilterStage::Ema { alpha, average } => {
+                let new_average = match average {
+                    Some(average) => *alpha * mm + (1.0 - *alpha) * *average,
+                    None => mm,
+                };
+                *average = Some(new_average);
+                reading.mm = new_average as u16;
+            }
+            FilterStage::RejectOutliers {
+                max_sigmas,
+                window,
+                history,
+            } => {
+                let reference = history.median(*window);
+                history.push(mm);
+                if let (Some(reference), Some(sigma)) = (reference, reading.sigma_mm)
+                    && (mm - reference).abs() > *max_sigmas * sigma.max(1.0)
+                {
+                    reading.quality = RangeQuality::Invalid;
+                }
+            }
+        }
+        reading
+    }
+}
+
+/// Filters applied in order to the readings of a sensor before they are published.
+///
+/// Only usable readings go through the stages: the others are published as they are and don't
+/// disturb the histories. A reading made unusable by a stage skips the following ones.
+#[derive(Debug, Clone, Default)]
+pub struct FilterChain {
+    stages: Vec<FilterStage, MAX_STAGES>,
+}
+
+impl FilterChain {
+    /// Applies `stages` in order, keeping the first [`MAX_STAGES`] ones
+    pub fn new(stages: &[FilterStage]) -> Self {
+        Self {
+            stages: stages.iter().take(MAX_STAGES).cloned().collect(),
+        }
+    }
+
+    /// Returns the same chain of filters, without history
+    pub fn cleared(&self) -> Self {
+        let mut chain = self.clone();
+        for stage in chain.stages.iter_mut() {
+            stage.reset();
+        }
+        chain
+    }
+
+    pub fn apply(&mut self, mut reading: DistanceReading) -> DistanceReading {
+        for stage in self.stages.iter_mut() {
+            if !reading.is_usable() {
+                break;
+            }
+            reading = stage.apply(reading);
+        }
+        reading
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+
+    fn reading(mm: u16, sigma_mm: Option<f32>) -> DistanceReading {
+        DistanceReading {
+            mm,
+            sigma_mm,
+            signal_rate_mcps: 10.0,
+            quality: RangeQuality::Valid,
+            zone: None,
+        }
+    }
+
+    fn run(chain: &mut FilterChain, readings: &[DistanceReading]) -> Vec<DistanceReading> {
+        readings
+            .iter()
+            .map(|reading| chain.apply(*reading))
+            .collect()
+    }
+
+    #[test]
+    fn history_keeps_the_most_recent_values() {
+        let mut history = History::new();
+        assert_eq!(history.median(3), None);
+        for value in 0..20 {
+            history.push(value as f32);
+        }
+        let recent: Vec<f32> = history.recent(4).collect();
+        assert_eq!(recent, vec![19.0, 18.0, 17.0, 16.0]);
+        assert_eq!(
+            history.recent(HISTORY_CAPACITY + 5).count(),
+            HISTORY_CAPACITY
+        );
+        assert_eq!(history.median(4), Some(17.5));
+        assert_eq!(history.median(3), Some(18.0));
+    }
+
+    #[test]
+    fn median_removes_a_spike() {
+        // Left VL53L0X along a wall, with one reflection off the next post
+        let readings = [84, 85, 84, 312, 85, 86, 85].map(|mm| reading(mm, None));
+        let mut chain = FilterChain::new(&[FilterStage::median(3)]);
+        let filtered: Vec<u16> = run(&mut chain, &readings)
+            .iter()
+            .map(|reading| reading.mm)
+            .collect();
+        assert_eq!(filtered, vec![84, 84, 84, 85, 85, 86, 85]);
+    }
+
+    #[test]
+    fn ema_starts_from_the_first_reading() {
+        let readings = [100, 200, 200].map(|mm| reading(mm, None));
+        let mut chain = FilterChain::new(&[FilterStage::ema(0.5)]);
+        let filtered: Vec<u16> = run(&mut chain, &readings)
+            .iter()
+            .map(|reading| reading.mm)
+            .collect();
+        assert_eq!(filtered, vec![100, 150, 175]);
+    }
+
+    #[test]
+    fn outliers_are_rejected_until_they_are_the_majority() {
+        // Front VL53L1X: a wall at 400 mm, then the robot turns to face one at 150 mm
+        let readings = [400, 401, 399, 400, 150, 151, 150, 149].map(|mm| reading(mm, Some(2.0)));
+        let mut chain = FilterChain::new(&[FilterStage::reject_outliers(3.0, 5)]);
+        let qualities: Vec<RangeQuality> = run(&mut chain, &readings)
+            .iter()
+            .map(|reading| reading.quality)
+            .collect();
+        use RangeQuality::{Invalid, Valid};
+        assert_eq!(
+            qualities,
+            vec![Valid, Valid, Valid, Valid, Invalid, Invalid, Invalid, Valid]
+        );
+    }
+
+    #[test]
+    fn readings_without_sigma_are_not_rejected() {
+        let readings = [400, 150].map(|mm| reading(mm, None));
+        let mut chain = FilterChain::new(&[FilterStage::reject_outliers(3.0, 5)]);
+        assert!(
+            run(&mut chain, &readings)
+                .iter()
+                .all(DistanceReading::is_usable)
+        );
+    }
+
+    #[test]
+    fn unusable_readings_skip_the_stages() {
+        let mut no_target = reading(8190, None);
+        no_target.quality = RangeQuality::NoTarget;
+        let readings = [reading(100, None), no_target, reading(102, None)];
+        let mut chain = FilterChain::new(&[FilterStage::median(3)]);
+        let filtered = run(&mut chain, &readings);
+        assert_eq!(filtered[1].mm, 8190);
+        assert_eq!(filtered[2].mm, 101);
+    }
+
+    #[test]
+    fn cleared_chain_forgets_the_history() {
+        let mut chain = FilterChain::new(&[FilterStage::ema(0.5)]);
+        chain.apply(reading(100, None));
+        let mut cleared = chain.cleared();
+        assert_eq!(cleared.apply(reading(200, None)).mm, 200);
+        assert_eq!(chain.apply(reading(200, None)).mm, 150);
+    }
+}
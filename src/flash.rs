@@ -0,0 +1,60 @@
+use core::cell::RefCell;
+use embassy_stm32::flash::{Blocking, Error, Flash};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embedded_storage::nor_flash::{ErrorType, NorFlash, ReadNorFlash};
+use static_cell::StaticCell;
+
+static FLASH: StaticCell<Mutex<CriticalSectionRawMutex, RefCell<Flash<'static, Blocking>>>> =
+    StaticCell::new();
+
+/// Handle to the internal flash, shared by the stores that persist calibrations in their own
+/// sectors.
+///
+/// Every operation holds the flash for its whole duration. They are blocking anyway: the CPU
+/// stalls on its next instruction fetch while the flash is being written or erased.
+#[derive(Clone, Copy)]
+pub struct SharedFlash {
+    flash: &'static Mutex<CriticalSectionRawMutex, RefCell<Flash<'static, Blocking>>>,
+}
+
+impl SharedFlash {
+    /// Takes the flash for the rest of the program, it can only be called once
+    pub fn new(flash: Flash<'static, Blocking>) -> Self {
+        Self {
+            flash: FLASH.init(Mutex::new(RefCell::new(flash))),
+        }
+    }
+}
+
+impl ErrorType for SharedFlash {
+    type Error = Error;
+}
+
+impl ReadNorFlash for SharedFlash {
+    const READ_SIZE: usize = <Flash<'static, Blocking> as ReadNorFlash>::READ_SIZE;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        self.flash
+            .lock(|flash| ReadNorFlash::read(&mut *flash.borrow_mut(), offset, bytes))
+    }
+
+    fn capacity(&self) -> usize {
+        self.flash.lock(|flash| flash.borrow().capacity())
+    }
+}
+
+impl NorFlash for SharedFlash {
+    const WRITE_SIZE: usize = <Flash<'static, Blocking> as NorFlash>::WRITE_SIZE;
+    const ERASE_SIZE: usize = <Flash<'static, Blocking> as NorFlash>::ERASE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        self.flash
+            .lock(|flash| NorFlash::erase(&mut *flash.borrow_mut(), from, to))
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.flash
+            .lock(|flash| NorFlash::write(&mut *flash.borrow_mut(), offset, bytes))
+    }
+}
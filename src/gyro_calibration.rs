@@ -0,0 +1,211 @@
+use defmt::{Format, warn};
+use embassy_time::{Duration, Instant};
+use mpu9250::MargMeasurements;
+
+/// Settings of the gyroscope bias calibration done when the IMU task starts
+#[derive(Debug, Clone, Copy, Format)]
+pub struct GyroCalibrationConfig {
+    /// Time over which the gyroscope is averaged, the robot must not move during it
+    pub window: Duration,
+    /// The window is rejected if an axis of the accelerometer varies by more than this during it,
+    /// in m/s². A rotation at constant speed around the vertical axis goes unnoticed.
+    pub max_accel_range: f32,
+    /// Rejected windows after which the calibration gives up
+    pub max_attempts: u8,
+}
+
+impl Default for GyroCalibrationConfig {
+    fn default() -> Self {
+        Self {
+            window: Duration::from_secs(2),
+            max_accel_range: 0.3,
+            max_attempts: 5,
+        }
+    }
+}
+
+/// Bias of the gyroscope, subtracted by the IMU task from every sample it publishes.
+///
+/// It drifts as the chip warms up and is not compensated: the drift with the temperature is
+/// specific to each board and wasn't measured on the robot.
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct GyroBias {
+    /// Bias of each axis, in rad/s
+    pub offset: [f32; 3],
+    /// Temperature of the chip during the calibration, in °C
+    pub temperature: f32,
+}
+
+impl GyroBias {
+    /// No correction, the gyroscope is published as the chip reports it
+    pub const NONE: GyroBias = GyroBias {
+        offset: [0.0; 3],
+        temperature: 0.0,
+    };
+
+    /// Removes the bias from the gyroscope of a sample
+    pub fn correct(&self, mut sample: MargMeasurements<[f32; 3]>) -> MargMeasurements<[f32; 3]> {
+        sample.gyro = core::array::from_fn(|axis| sample.gyro[axis] - self.offset[axis]);
+        sample
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum GyroCalibrationError {
+    /// The robot moved during every attempt
+    Motion,
+}
+
+/// Averages the gyroscope over a window during which the robot stays still
+pub struct GyroCalibration {
+    config: GyroCalibrationConfig,
+    start: Option<Instant>,
+    gyro_sum: [f32; 3],
+    temperature_sum: f32,
+    samples: u32,
+    accel_min: [f32; 3],
+    accel_max: [f32; 3],
+    rejected: u8,
+}
+
+impl GyroCalibration {
+    pub fn new(config: GyroCalibrationConfig) -> Self {
+        Self {
+            config,
+            start: None,
+            gyro_sum: [0.0; 3],
+            temperature_sum: 0.0,
+            samples: 0,
+            accel_min: [f32::INFINITY; 3],
+            accel_max: [f32::NEG_INFINITY; 3],
+            rejected: 0,
+        }
+    }
+
+    /// Adds a sample taken at `timestamp`.
+    ///
+    /// Returns the bias once a whole window was still. A window during which the robot moved is
+    /// started again, until [`GyroCalibrationConfig::max_attempts`] windows were rejected.
+    pub fn update(
+        &mut self,
+        sample: &MargMeasurements<[f32; 3]>,
+        timestamp: Instant,
+    ) -> Option<Result<GyroBias, GyroCalibrationError>> {
+        let start = *self.start.get_or_insert(timestamp);
+        for axis in 0..3 {
+            self.accel_min[axis] = self.accel_min[axis].min(sample.accel[axis]);
+            self.accel_max[axis] = self.accel_max[axis].max(sample.accel[axis]);
+            self.gyro_sum[axis] += sample.gyro[axis];
+        }
+        self.temperature_sum += sample.temp;
+        self.samples += 1;
+
+        let moved = (0..3)
+            .any(|axis| self.accel_max[axis] - self.accel_min[axis] > self.config.max_accel_range);
+        if moved {
+            self.rejected += 1;
+            if self.rejected >= self.config.max_attempts {
+                return Some(Err(GyroCalibrationError::Motion));
+            }
+            warn!(
+                "Motion during the gyroscope calibration, attempt {}/{}",
+                self.rejected, self.config.max_attempts
+            );
+            self.restart();
+            return None;
+        }
+        if timestamp.saturating_duration_since(start) < self.config.window {
+            return None;
+        }
+        let samples = self.samples as f32;
+        Some(Ok(GyroBias {
+            offset: self.gyro_sum.map(|sum| sum / samples),
+            temperature: self.temperature_sum / samples,
+        }))
+    }
+
+    /// Starts a new window with the next sample, keeping the count of rejected ones
+    fn restart(&mut self) {
+        *self = Self {
+            rejected: self.rejected,
+            ..Self::new(self.config)
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Gyroscope of an IMU lying still at 100 Hz, in rad/s
+    const STILL_GYRO: [[f32; 3]; 4] = [
+        [0.012, -0.021, 0.005],
+        [0.014, -0.019, 0.003],
+        [0.011, -0.020, 0.006],
+        [0.013, -0.020, 0.006],
+    ];
+    const GRAVITY: [f32; 3] = [0.1, -0.2, 9.8];
+
+    fn sample(gyro: [f32; 3], accel: [f32; 3], temp: f32) -> MargMeasurements<[f32; 3]> {
+        MargMeasurements {
+            accel,
+            gyro,
+            mag: [0.0; 3],
+            temp,
+        }
+    }
+
+    /// Feeds `accel` with the still gyroscope every 10 ms until the calibration ends
+    fn calibrate(
+        config: GyroCalibrationConfig,
+        accel: impl Fn(u64) -> [f32; 3],
+    ) -> (Result<GyroBias, GyroCalibrationError>, u64) {
+        let mut calibration = GyroCalibration::new(config);
+        for index in 0.. {
+            let sample = sample(STILL_GYRO[index as usize % 4], accel(index), 30.0);
+            if let Some(result) = calibration.update(&sample, Instant::from_millis(10 * index)) {
+                return (result, index);
+            }
+        }
+        unreachable!()
+    }
+
+    #[test]
+    fn still_window_gives_the_average() {
+        let (result, index) = calibrate(GyroCalibrationConfig::default(), |_| GRAVITY);
+        let bias = result.unwrap();
+        assert_eq!(index, 200);
+        for (offset, expected) in bias.offset.iter().zip([0.0125, -0.02, 0.005]) {
+            assert!((offset - expected).abs() < 1e-4, "{offset} != {expected}");
+        }
+        assert_eq!(bias.temperature, 30.0);
+    }
+
+    #[test]
+    fn motion_restarts_the_window() {
+        // Bumped once after half a second
+        let bump = |index| {
+            if index == 50 {
+                [1.0, -0.2, 9.8]
+            } else {
+                GRAVITY
+            }
+        };
+        let (result, index) = calibrate(GyroCalibrationConfig::default(), bump);
+        assert!(result.is_ok());
+        assert_eq!(index, 251);
+    }
+
+    #[test]
+    fn gives_up_when_the_robot_keeps_moving() {
+        let shaking = |index| {
+            if index % 2 == 0 {
+                GRAVITY
+            } else {
+                [0.1, 0.5, 9.8]
+            }
+        };
+        let (result, _) = calibrate(GyroCalibrationConfig::default(), shaking);
+        assert_eq!(result, Err(GyroCalibrationError::Motion));
+    }
+}
@@ -0,0 +1,294 @@
+use defmt::{Format, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::{Mutex, MutexGuard};
+use embedded_hal::i2c::{Error, ErrorKind, ErrorType, I2c, Operation};
+use embedded_hal_async::i2c::I2c as AsyncI2c;
+use heapless::Vec;
+
+/// Error of a transfer on a [`SharedI2c`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BusError<E> {
+    I2c(E),
+    /// A blocking transfer was attempted while an async one was in flight.
+    /// Call [`SharedBus::acquire`] before blocking transfers to avoid it.
+    Busy,
+}
+
+impl<E: Error> Error for BusError<E> {
+    fn kind(&self) -> ErrorKind {
+        match self {
+            BusError::I2c(e) => e.kind(),
+            BusError::Busy => ErrorKind::Other,
+        }
+    }
+}
+
+/// A bus handle usable both by the blocking driver crates and by async code
+pub trait SharedBus: I2c + AsyncI2c + Clone {
+    /// Waits until no async transfer is in flight.
+    ///
+    /// The executor is single-threaded, so blocking transfers made right after, without awaiting
+    /// in between, always find the bus free.
+    async fn acquire(&mut self);
+}
+
+/// Handle to an I2C bus shared by several drivers running on the same executor.
+///
+/// The bus is behind an async mutex: async transfers wait for their turn without stalling the
+/// executor, and DMA transfers keep the other tasks running while they are in flight. It can be
+/// copied, so that a driver can keep a spare handle to re-create its device after resetting the
+/// chip.
+pub struct SharedI2c<BUS: 'static> {
+    bus: &'static Mutex<CriticalSectionRawMutex, BUS>,
+}
+
+impl<BUS> SharedI2c<BUS> {
+    pub fn new(bus: &'static Mutex<CriticalSectionRawMutex, BUS>) -> Self {
+        Self { bus }
+    }
+
+    /// Locks the bus for a blocking transfer
+    fn try_lock(
+        &self,
+    ) -> Result<MutexGuard<'static, CriticalSectionRawMutex, BUS>, BusError<BUS::Error>>
+    where
+        BUS: ErrorType,
+    {
+        self.bus.try_lock().map_err(|_| BusError::Busy)
+    }
+}
+
+impl<BUS> Clone for SharedI2c<BUS> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<BUS> Copy for SharedI2c<BUS> {}
+
+impl<BUS: ErrorType> ErrorType for SharedI2c<BUS> {
+    type Error = BusError<BUS::Error>;
+}
+
+impl<BUS: I2c> I2c for SharedI2c<BUS> {
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.try_lock()?.read(address, read).map_err(BusError::I2c)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.try_lock()?
+            .write(address, write)
+            .map_err(BusError::I2c)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.try_lock()?
+            .write_read(address, write, read)
+            .map_err(BusError::I2c)
+    }
+
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.try_lock()?
+            .transaction(address, operations)
+            .map_err(BusError::I2c)
+    }
+}
+
+impl<BUS: AsyncI2c> AsyncI2c for SharedI2c<BUS> {
+    async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.bus
+            .lock()
+            .await
+            .read(address, read)
+            .await
+            .map_err(BusError::I2c)
+    }
+
+    async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.bus
+            .lock()
+            .await
+            .write(address, write)
+            .await
+            .map_err(BusError::I2c)
+    }
+
+    async fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.bus
+            .lock()
+            .await
+            .write_read(address, write, read)
+            .await
+            .map_err(BusError::I2c)
+    }
+
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.bus
+            .lock()
+            .await
+            .transaction(address, operations)
+            .await
+            .map_err(BusError::I2c)
+    }
+}
+
+impl<BUS: I2c + AsyncI2c> SharedBus for SharedI2c<BUS> {
+    async fn acquire(&mut self) {
+        drop(self.bus.lock().await);
+    }
+}
+
+/// Consecutive bus errors after which [`RecoveringI2c`] recovers the bus
+const RECOVER_AFTER: u8 = 3;
+
+/// I2C bus getting itself out of a stuck state after repeated errors.
+///
+/// A device reset in the middle of a transfer can hold SDA low until the next power cycle. After
+/// [`RECOVER_AFTER`] consecutive errors, the peripheral is dropped and `rebuild` is called to
+/// clock the stuck device out and create the peripheral again. A missing acknowledge doesn't count
+/// as an error: the bus is working, nobody answered.
+pub struct RecoveringI2c<BUS> {
+    /// Only `None` while the bus is being rebuilt
+    bus: Option<BUS>,
+    rebuild: fn() -> BUS,
+    consecutive_errors: u8,
+}
+
+impl<BUS: ErrorType> RecoveringI2c<BUS> {
+    pub fn new(bus: BUS, rebuild: fn() -> BUS) -> Self {
+        Self {
+            bus: Some(bus),
+            rebuild,
+            consecutive_errors: 0,
+        }
+    }
+
+    fn bus(&mut self) -> &mut BUS {
+        self.bus.as_mut().unwrap()
+    }
+
+    /// Counts the errors of a transfer and recovers the bus when they pile up
+    fn check<T>(&mut self, result: Result<T, BUS::Error>) -> Result<T, BUS::Error> {
+        match &result {
+            Err(e) if !matches!(e.kind(), ErrorKind::NoAcknowledge(_)) => {
+                self.consecutive_errors += 1;
+                if self.consecutive_errors >= RECOVER_AFTER {
+                    self.recover();
+                }
+            }
+            _ => self.consecutive_errors = 0,
+        }
+        result
+    }
+
+    /// Drops the peripheral, so that its pins are released, and builds it again
+    pub fn recover(&mut self) {
+        warn!(
+            "I2C bus failed {} times in a row, recovering it",
+            self.consecutive_errors
+        );
+        self.bus = None;
+        self.bus = Some((self.rebuild)());
+        self.consecutive_errors = 0;
+    }
+}
+
+impl<BUS: ErrorType> ErrorType for RecoveringI2c<BUS> {
+    type Error = BUS::Error;
+}
+
+impl<BUS: I2c> I2c for RecoveringI2c<BUS> {
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.bus().read(address, read);
+        self.check(result)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        let result = self.bus().write(address, write);
+        self.check(result)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.bus().write_read(address, write, read);
+        self.check(result)
+    }
+
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.bus().transaction(address, operations);
+        self.check(result)
+    }
+}
+
+impl<BUS: AsyncI2c> AsyncI2c for RecoveringI2c<BUS> {
+    async fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        let result = self.bus().read(address, read).await;
+        self.check(result)
+    }
+
+    async fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        let result = self.bus().write(address, write).await;
+        self.check(result)
+    }
+
+    async fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        let result = self.bus().write_read(address, write, read).await;
+        self.check(result)
+    }
+
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = self.bus().transaction(address, operations).await;
+        self.check(result)
+    }
+}
+
+/// Range of the addresses probed by [`scan`], the others are reserved
+const SCAN_START: u8 = 0x08;
+const SCAN_END: u8 = 0x78;
+
+/// Returns the address of every device answering on the bus, skipping the reserved addresses
+pub async fn scan<I: AsyncI2c>(i2c: &mut I) -> Vec<u8, { (SCAN_END - SCAN_START) as usize }> {
+    let mut found = Vec::new();
+    for address in SCAN_START..SCAN_END {
+        // A one byte read is harmless for the devices of the robot, unlike a write
+        if i2c.read(address, &mut [0]).await.is_ok() {
+            let _ = found.push(address);
+        }
+    }
+    found
+}
@@ -1,102 +1,295 @@
-use crate::sensor::Sensor;
-use crate::sensor::vl53lxx::TimingConfig;
+use crate::Irqs;
+use crate::i2c_bus::{BusError, RecoveringI2c, SharedI2c, scan};
+use crate::sensor::channel::{Subscription, Timestamped};
+use crate::sensor::distance::{DistanceReading, SensorPosition, register_distance_sensor};
+use crate::sensor::health::{SensorId, monitor};
+use crate::sensor::vl53lxx::bringup::{
+    FailedSensor, MAX_SENSORS, SensorSlot, ToFSensor, bring_up, wake_up,
+};
 use crate::sensor::vl53lxx::vl53l0x::VL53L0XSensor;
 use crate::sensor::vl53lxx::vl53l1x::VL53L1XSensor;
-use crate::{Irqs, sensor};
-use alloc::boxed::Box;
-use alloc::vec::Vec;
-use core::cell::RefCell;
-use defmt::{error, info};
+use crate::sensor::{sensor_task, start_on_heap};
+use defmt::{debug, error, info, warn};
 use embassy_executor::Spawner;
 use embassy_stm32::Peri;
 use embassy_stm32::exti::ExtiInput;
-use embassy_stm32::gpio::{Output, Speed};
-use embassy_stm32::i2c::{Config, I2c};
+use embassy_stm32::gpio::{Level, Output, OutputOpenDrain, Speed};
+use embassy_stm32::i2c;
+use embassy_stm32::i2c::{Config, I2c, Master};
+use embassy_stm32::mode::Async;
 use embassy_stm32::peripherals::{DMA1_CH0, DMA1_CH6, I2C1, PB8, PB9};
 use embassy_stm32::time::Hertz;
-use embedded_hal_bus::i2c::RefCellDevice;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::Mutex;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{Duration, Timer, block_for};
+use heapless::Vec;
+use micromouse::vl53lxx_bus::DEFAULT_ADDRESS;
+use static_cell::StaticCell;
 
+/// Device type of the sensors sharing the I2C1 bus
+pub(crate) type I2c1Device = SharedI2c<RecoveringI2c<I2c<'static, Async, Master>>>;
+/// XSHUT pin of the sensors on I2C1
+pub(crate) type I2c1Xshut = Output<'static>;
+/// Data-ready interrupt pin of the sensors on I2C1
+pub(crate) type I2c1Interrupt = ExtiInput<'static>;
+/// A sensor on I2C1 that was brought up, whatever its chip
+pub(crate) type I2c1Sensor = ToFSensor<I2c1Device, I2c1Xshut, I2c1Interrupt>;
+
+sensor_task!(
+    vl53l0x_task,
+    VL53L0XSensor<I2c1Device, I2c1Xshut, I2c1Interrupt>,
+    MAX_SENSORS
+);
+sensor_task!(
+    vl53l1x_task,
+    VL53L1XSensor<I2c1Device, I2c1Xshut, I2c1Interrupt>,
+    MAX_SENSORS
+);
+
+/// The sensors need a 'static reference to the bus
+static I2C1_BUS: StaticCell<
+    Mutex<CriticalSectionRawMutex, RecoveringI2c<I2c<'static, Async, Master>>>,
+> = StaticCell::new();
+
+/// Number of distance sensors on I2C1, the size of the bring-up table
+pub const I2C1_SENSORS: usize = 2;
+
+/// Time between two attempts to bring up the distance sensors that failed
+const RETRY_PERIOD: Duration = Duration::from_secs(5);
+
+/// Which distance sensors came up at boot
+pub struct DistanceSensorsReport {
+    pub available: Vec<SensorPosition, I2C1_SENSORS>,
+    /// These sensors are retried in the background, see [`crate::sensor::distance::distance_sensor`]
+    pub unavailable: Vec<SensorPosition, I2C1_SENSORS>,
+}
+
+/// Brings up every distance sensor of `slots` on I2C1 and starts the ones that answered.
+///
+/// A sensor that fails doesn't prevent the others from running: it is left in reset, marked
+/// unavailable, and its bring-up is retried periodically in the background. So is the start of a
+/// sensor that came up but could not be started.
 pub async fn init_i2c_devices(
-    mut spawner: &mut Spawner,
+    spawner: &mut Spawner,
     i2c_peri: Peri<'static, I2C1>,
     scl: Peri<'static, PB8>,
     sda: Peri<'static, PB9>,
     tx_dma: Peri<'static, DMA1_CH6>,
     rx_dma: Peri<'static, DMA1_CH0>,
     irqs: Irqs,
-    mut xshuts: Vec<Output<'static>>,
-    mut interrupts: Vec<ExtiInput<'static>>,
-) {
+    slots: [SensorSlot<I2c1Xshut, I2c1Interrupt>; I2C1_SENSORS],
+) -> DistanceSensorsReport {
+    let i2c = I2c::new(i2c_peri, scl, sda, irqs, tx_dma, rx_dma, i2c_config());
+    let i2c = RecoveringI2c::new(i2c, rebuild_i2c1);
+
+    let mut i2c = SharedI2c::new(I2C1_BUS.init(Mutex::new(i2c)));
+
+    info!("Bringing up {} distance sensors...", slots.len());
+    let report = bring_up(slots, i2c).await;
+    log_bus_scan(&mut i2c).await;
+
+    info!("Starting continuous measurement");
+    let mut available = Vec::new();
+    let mut unavailable: Vec<SensorPosition, I2C1_SENSORS> = report
+        .failed
+        .iter()
+        .map(|failed| failed.slot.position)
+        .collect();
+    let mut not_started = Vec::new();
+    // Every list has room for all the sensors
+    for (position, sensor) in report.sensors {
+        match start_sensor(spawner, position, sensor).await {
+            Ok(()) => {
+                let _ = available.push(position);
+            }
+            Err(sensor) => {
+                let _ = unavailable.push(position);
+                let _ = not_started.push((position, sensor));
+            }
+        }
+    }
+
+    if !report.failed.is_empty() || !not_started.is_empty() {
+        spawner
+            .spawn(retry_failed_sensors_task(
+                *spawner,
+                i2c,
+                report.failed,
+                not_started,
+            ))
+            .unwrap();
+    }
+
+    DistanceSensorsReport {
+        available,
+        unavailable,
+    }
+}
+
+fn i2c_config() -> Config {
     let mut i2c_config = Config::default();
     // Use 100kHz for more reliable communication
     i2c_config.frequency = Hertz::khz(200);
     i2c_config.gpio_speed = Speed::High;
+    i2c_config
+}
+
+/// Frees the bus from a device holding SDA low, then creates the I2C1 peripheral again.
+/// Called by [`RecoveringI2c`] once the previous peripheral is dropped.
+fn rebuild_i2c1() -> I2c<'static, Async, Master> {
+    // SAFETY: the peripheral owning them was just dropped, nothing else uses them
+    let (i2c_peri, mut scl, mut sda, tx_dma, rx_dma) = unsafe {
+        (
+            I2C1::steal(),
+            PB8::steal(),
+            PB9::steal(),
+            DMA1_CH6::steal(),
+            DMA1_CH0::steal(),
+        )
+    };
+    {
+        let mut scl = OutputOpenDrain::new(scl.reborrow(), Level::High, Speed::Low);
+        let mut sda = OutputOpenDrain::new(sda.reborrow(), Level::High, Speed::Low);
+        let half_period = Duration::from_micros(5);
 
-    let i2c = I2c::new(i2c_peri, scl, sda, irqs, tx_dma, rx_dma, i2c_config);
+        // A device in the middle of a read releases SDA after at most 9 clock pulses
+        for _ in 0..9 {
+            if sda.is_high() {
+                break;
+            }
+            scl.set_low();
+            block_for(half_period);
+            scl.set_high();
+            block_for(half_period);
+        }
+        if sda.is_low() {
+            error!("I2C1 SDA still held low after clocking SCL");
+        }
 
-    // Leak i2c_rc to get a 'static reference, required for the sensor
-    let i2c_rc = Box::leak(Box::new(RefCell::new(i2c)));
+        // STOP condition: SDA rising while SCL is high
+        scl.set_low();
+        sda.set_low();
+        block_for(half_period);
+        scl.set_high();
+        block_for(half_period);
+        sda.set_high();
+        block_for(half_period);
+    }
+    I2c::new(i2c_peri, scl, sda, Irqs, tx_dma, rx_dma, i2c_config())
+}
 
-    // Initialize the distance sensor using the trait-based API
-    info!("Initializing distance sensors...");
+/// Logs every device answering on the bus, so that wiring problems are obvious
+async fn log_bus_scan(i2c: &mut I2c1Device) {
+    let addresses = scan(i2c).await;
+    info!("I2C1 scan: {} device(s) answering", addresses.len());
+    for address in addresses {
+        if address == DEFAULT_ADDRESS {
+            warn!(
+                "  {=u8:#04x}: a ToF sensor kept the default address, check its XSHUT wiring",
+                address
+            );
+        } else {
+            info!("  {=u8:#04x}", address);
+        }
+    }
+}
 
-    let sensor0 = match VL53L0XSensor::init_new(
-        sensor::vl53lxx::Config {
-            timing_config: TimingConfig::default(),
-            xshut_pin: xshuts.remove(0),
-            gpio_interrupt: interrupts.remove(0),
+/// Starts the task of a sensor that was brought up and registers it as available. The sensor is
+/// given back if it could not be started.
+async fn start_sensor(
+    spawner: &mut Spawner,
+    position: SensorPosition,
+    sensor: I2c1Sensor,
+) -> Result<(), I2c1Sensor> {
+    // Subscribe before starting so that the first measurements are not missed
+    let log = sensor.handle().subscribe().unwrap();
+    let handle = match sensor {
+        ToFSensor::VL53L0X(sensor) => match start_on_heap(spawner, sensor, vl53l0x_task).await {
+            Ok(handle) => handle,
+            Err((e, sensor)) => {
+                error!("Failed to start {} distance sensor: {}", position, e);
+                return Err(ToFSensor::VL53L0X(sensor));
+            }
+        },
+        ToFSensor::VL53L1X(sensor) => match start_on_heap(spawner, sensor, vl53l1x_task).await {
+            Ok(handle) => handle,
+            Err((e, sensor)) => {
+                error!("Failed to start {} distance sensor: {}", position, e);
+                return Err(ToFSensor::VL53L1X(sensor));
+            }
         },
-        RefCellDevice::new(i2c_rc),
-    )
-        .await
-    {
-        Ok(s) => {
-            info!("Distance sensor 0 initialized successfully");
-            Box::leak(Box::new(s))
-        }
-        Err(e) => {
-            error!("Failed to initialize distance 0 sensor: {}", e);
-            core::panic!("Sensor initialization failed");
-        }
     };
+    register_distance_sensor(position, handle);
+    monitor(SensorId::Distance(position), handle.health());
+    spawner.spawn(log_distance_task(position, log)).unwrap();
+    Ok(())
+}
 
-    let sensor1 = match VL53L1XSensor::init_new(
-        sensor::vl53lxx::Config {
-            timing_config: TimingConfig::default(),
-            xshut_pin: xshuts.remove(0),
-            gpio_interrupt: interrupts.remove(0),
-        },
-        RefCellDevice::new(i2c_rc),
-    )
-        .await
-    {
-        Ok(s) => {
-            info!("Distance sensor 1 initialized successfully");
-            Box::leak(Box::new(s))
+/// Retries the bring-up of the sensors in `failed`, and the start of the sensors in `not_started`
+/// that were brought up, until they all run
+#[embassy_executor::task]
+async fn retry_failed_sensors_task(
+    mut spawner: Spawner,
+    i2c: I2c1Device,
+    mut failed: Vec<FailedSensor<BusError<i2c::Error>, I2c1Xshut, I2c1Interrupt>, I2C1_SENSORS>,
+    mut not_started: Vec<(SensorPosition, I2c1Sensor), I2C1_SENSORS>,
+) {
+    while !failed.is_empty() || !not_started.is_empty() {
+        Timer::after(RETRY_PERIOD).await;
+        // Every sensor is taken out of its list and put back at the end if it still fails, the
+        // lists never hold more than all the sensors
+        for _ in 0..not_started.len() {
+            let (position, sensor) = not_started.remove(0);
+            if let Err(sensor) = start_sensor(&mut spawner, position, sensor).await {
+                let _ = not_started.push((position, sensor));
+            }
         }
-        Err(e) => {
-            error!("Failed to initialize distance 1 sensor: {}", e);
-            core::panic!("Sensor initialization failed");
+        for _ in 0..failed.len() {
+            let FailedSensor { slot, .. } = failed.remove(0);
+            let position = slot.position;
+            match wake_up(slot, i2c).await {
+                Ok(sensor) => {
+                    info!("{} distance sensor came up on retry", position);
+                    if let Err(sensor) = start_sensor(&mut spawner, position, sensor).await {
+                        let _ = not_started.push((position, sensor));
+                    }
+                }
+                Err(still_failed) => {
+                    debug!(
+                        "{} distance sensor still failing: {}",
+                        position, still_failed.error
+                    );
+                    let _ = failed.push(still_failed);
+                }
+            }
         }
-    };
+    }
+    info!("All distance sensors are up");
+}
 
-    info!("Starting continuous measurement");
-    sensor0
-        .start_continuous_measurement(&mut spawner, &|data| {
-            info!("New measurement: {} mm {}", data.distance_mm, data.status);
-        })
-        .await
-        .unwrap();
-
-    sensor1
-        .start_continuous_measurement(&mut spawner, &|data| {
-            info!(
-                "New measurement: {} mm {} σ={}",
-                data.range_milli_meter,
-                data.range_status,
-                data.sigma_milli_meter as f32 / 65536.0
-            );
-        })
-        .await
-        .unwrap();
+#[embassy_executor::task(pool_size = MAX_SENSORS)]
+async fn log_distance_task(
+    sensor: SensorPosition,
+    mut subscription: Subscription<DistanceReading>,
+) -> ! {
+    loop {
+        match subscription.next().await {
+            WaitResult::Message(Timestamped {
+                measurement: data, ..
+            }) => match data.sigma_mm {
+                Some(sigma) => info!(
+                    "Sensor {}: {} mm {} σ={}",
+                    sensor, data.mm, data.quality, sigma
+                ),
+                None => info!("Sensor {}: {} mm {}", sensor, data.mm, data.quality),
+            },
+            WaitResult::Lagged(count) => {
+                warn!(
+                    "Sensor {} logger lagged, {} measurements missed",
+                    sensor, count
+                )
+            }
+        }
+    }
 }
@@ -0,0 +1,52 @@
+//! Parts of the sensors that don't depend on the microcontroller: the processing of the readings
+//! and the drivers that only need a bus. It also builds for the host, where its tests run on
+//! sequences of readings and mock buses: `cargo test-host`.
+#![no_std]
+extern crate alloc;
+
+pub mod filter;
+pub mod gyro_calibration;
+pub mod madgwick;
+pub mod mag_calibration;
+pub mod mpu9250_fifo;
+pub mod reading;
+pub mod vl53lxx_bus;
+pub mod wall_detector;
+
+/// What the firmware provides to defmt and embassy-time. The logs of the tests are dropped and
+/// their clock stays at one second.
+#[cfg(test)]
+mod test_support {
+    use core::task::Waker;
+
+    #[defmt::global_logger]
+    struct Discard;
+
+    unsafe impl defmt::Logger for Discard {
+        fn acquire() {}
+        unsafe fn flush() {}
+        unsafe fn release() {}
+        unsafe fn write(_bytes: &[u8]) {}
+    }
+
+    defmt::timestamp!("");
+
+    #[defmt::panic_handler]
+    fn panic() -> ! {
+        panic!("defmt panic")
+    }
+
+    struct FixedClock;
+
+    impl embassy_time_driver::Driver for FixedClock {
+        fn now(&self) -> u64 {
+            embassy_time_driver::TICK_HZ
+        }
+
+        fn schedule_wake(&self, _at: u64, waker: &Waker) {
+            waker.wake_by_ref();
+        }
+    }
+
+    embassy_time_driver::time_driver_impl!(static CLOCK: FixedClock = FixedClock);
+}
@@ -0,0 +1,313 @@
+use defmt::Format;
+// The tests link std, whose float methods shadow these
+#[cfg_attr(test, allow(unused_imports))]
+use micromath::F32Ext;
+use mpu9250::MargMeasurements;
+
+/// Rotation from the earth frame to the IMU frame
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Quaternion {
+    pub w: f32,
+    pub x: f32,
+    pub y: f32,
+    pub z: f32,
+}
+
+impl Quaternion {
+    pub const IDENTITY: Quaternion = Quaternion {
+        w: 1.0,
+        x: 0.0,
+        y: 0.0,
+        z: 0.0,
+    };
+
+    fn normalized(self) -> Quaternion {
+        let norm = (self.w * self.w + self.x * self.x + self.y * self.y + self.z * self.z).sqrt();
+        if norm == 0.0 {
+            return Quaternion::IDENTITY;
+        }
+        Quaternion {
+            w: self.w / norm,
+            x: self.x / norm,
+            y: self.y / norm,
+            z: self.z / norm,
+        }
+    }
+}
+
+/// Orientation of the IMU, as a quaternion and as yaw, pitch and roll applied in that order
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Orientation {
+    pub quaternion: Quaternion,
+    /// Counterclockwise around the vertical axis, from -π to π
+    pub yaw_rad: f32,
+    /// Nose up around the lateral axis, from -π/2 to π/2
+    pub pitch_rad: f32,
+    /// Around the longitudinal axis, from -π to π
+    pub roll_rad: f32,
+}
+
+impl From<Quaternion> for Orientation {
+    fn from(q: Quaternion) -> Self {
+        Self {
+            quaternion: q,
+            yaw_rad: (2.0 * (q.w * q.z + q.x * q.y)).atan2(1.0 - 2.0 * (q.y * q.y + q.z * q.z)),
+            pitch_rad: (2.0 * (q.w * q.y - q.z * q.x)).clamp(-1.0, 1.0).asin(),
+            roll_rad: (2.0 * (q.w * q.x + q.y * q.z)).atan2(1.0 - 2.0 * (q.x * q.x + q.y * q.y)),
+        }
+    }
+}
+
+/// Settings of the orientation filter
+#[derive(Debug, Clone, Copy, Format)]
+pub struct AhrsConfig {
+    /// How fast the accelerometer and the magnetometer pull the orientation integrated from the
+    /// gyroscope, in rad/s. Higher converges faster but lets more accelerometer noise through.
+    pub gain: f32,
+}
+
+impl Default for AhrsConfig {
+    fn default() -> Self {
+        Self { gain: 0.1 }
+    }
+}
+
+/// Madgwick orientation filter: integrates the gyroscope and corrects its drift with a gradient
+/// descent step towards the gravity and the magnetic field.
+///
+/// Without magnetometer, i.e. when it is zero, only the pitch and the roll are corrected and the
+/// yaw drifts with the remaining gyroscope bias.
+pub struct Madgwick {
+    gain: f32,
+    quaternion: Quaternion,
+}
+
+impl Madgwick {
+    pub fn new(config: AhrsConfig) -> Self {
+        Self {
+            gain: config.gain,
+            quaternion: Quaternion::IDENTITY,
+        }
+    }
+
+    /// Integrates a sample taken `dt_s` seconds after the previous one
+    pub fn update(&mut self, sample: &MargMeasurements<[f32; 3]>, dt_s: f32) -> Orientation {
+        let [gx, gy, gz] = sample.gyro;
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = self.quaternion;
+        // Rate of change of the quaternion from the gyroscope
+        let mut rate = [
+            0.5 * (-q1 * gx - q2 * gy - q3 * gz),
+            0.5 * (q0 * gx + q2 * gz - q3 * gy),
+            0.5 * (q0 * gy - q1 * gz + q3 * gx),
+            0.5 * (q0 * gz + q1 * gy - q2 * gx),
+        ];
+        let step = match (normalized(sample.accel), normalized(align_mag(sample.mag))) {
+            (Some(accel), Some(mag)) => Some(self.marg_gradient(accel, mag)),
+            (Some(accel), None) => Some(self.imu_gradient(accel)),
+            (None, _) => None,
+        };
+        if let Some(step) = step {
+            let norm = step.iter().map(|s| s * s).sum::<f32>().sqrt();
+            if norm > 0.0 {
+                for (rate, s) in rate.iter_mut().zip(step) {
+                    *rate -= self.gain * s / norm;
+                }
+            }
+        }
+        self.quaternion = Quaternion {
+            w: q0 + rate[0] * dt_s,
+            x: q1 + rate[1] * dt_s,
+            y: q2 + rate[2] * dt_s,
+            z: q3 + rate[3] * dt_s,
+        }
+        .normalized();
+        self.quaternion.into()
+    }
+
+    /// Gradient of the error between the measured gravity and the one expected from the
+    /// orientation
+    fn imu_gradient(&self, [ax, ay, az]: [f32; 3]) -> [f32; 4] {
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = self.quaternion;
+        let (q0q0, q1q1, q2q2, q3q3) = (q0 * q0, q1 * q1, q2 * q2, q3 * q3);
+        [
+            4.0 * q0 * q2q2 + 2.0 * q2 * ax + 4.0 * q0 * q1q1 - 2.0 * q1 * ay,
+            4.0 * q1 * q3q3 - 2.0 * q3 * ax + 4.0 * q0q0 * q1 - 2.0 * q0 * ay - 4.0 * q1
+                + 8.0 * q1 * q1q1
+                + 8.0 * q1 * q2q2
+                + 4.0 * q1 * az,
+            4.0 * q0q0 * q2 + 2.0 * q0 * ax + 4.0 * q2 * q3q3 - 2.0 * q3 * ay - 4.0 * q2
+                + 8.0 * q2 * q1q1
+                + 8.0 * q2 * q2q2
+                + 4.0 * q2 * az,
+            4.0 * q1q1 * q3 - 2.0 * q1 * ax + 4.0 * q2q2 * q3 - 2.0 * q2 * ay,
+        ]
+    }
+
+    /// Same as [`Self::imu_gradient`], with the error of the magnetic field added. The field is
+    /// taken in the earth frame as horizontal towards x plus vertical, so that only the heading of
+    /// the magnetometer matters.
+    fn marg_gradient(&self, [ax, ay, az]: [f32; 3], [mx, my, mz]: [f32; 3]) -> [f32; 4] {
+        let Quaternion {
+            w: q0,
+            x: q1,
+            y: q2,
+            z: q3,
+        } = self.quaternion;
+        let (q0q0, q0q1, q0q2, q0q3) = (q0 * q0, q0 * q1, q0 * q2, q0 * q3);
+        let (q1q1, q1q2, q1q3) = (q1 * q1, q1 * q2, q1 * q3);
+        let (q2q2, q2q3, q3q3) = (q2 * q2, q2 * q3, q3 * q3);
+
+        // Magnetic field in the earth frame, then twice its horizontal and vertical components
+        let hx = mx * q0q0 - 2.0 * q0 * my * q3
+            + 2.0 * q0 * mz * q2
+            + mx * q1q1
+            + 2.0 * q1 * my * q2
+            + 2.0 * q1 * mz * q3
+            - mx * q2q2
+            - mx * q3q3;
+        let hy = 2.0 * q0 * mx * q3 + my * q0q0 - 2.0 * q0 * mz * q1 + 2.0 * q1 * mx * q2
+            - my * q1q1
+            + my * q2q2
+            + 2.0 * q2 * mz * q3
+            - my * q3q3;
+        let hz = -2.0 * q0 * mx * q2 + 2.0 * q0 * my * q1 + mz * q0q0 + 2.0 * q1 * mx * q3
+            - mz * q1q1
+            + 2.0 * q2 * my * q3
+            - mz * q2q2
+            + mz * q3q3;
+        let bx = 2.0 * (hx * hx + hy * hy).sqrt();
+        let bz = 2.0 * hz;
+
+        // Errors of the gravity and of the magnetic field
+        let fax = 2.0 * (q1q3 - q0q2) - ax;
+        let fay = 2.0 * (q0q1 + q2q3) - ay;
+        let faz = 1.0 - 2.0 * (q1q1 + q2q2) - az;
+        let fmx = bx * (0.5 - q2q2 - q3q3) + bz * (q1q3 - q0q2) - mx;
+        let fmy = bx * (q1q2 - q0q3) + bz * (q0q1 + q2q3) - my;
+        let fmz = bx * (q0q2 + q1q3) + bz * (0.5 - q1q1 - q2q2) - mz;
+        [
+            -2.0 * q2 * fax + 2.0 * q1 * fay - bz * q2 * fmx
+                + (-bx * q3 + bz * q1) * fmy
+                + bx * q2 * fmz,
+            2.0 * q3 * fax + 2.0 * q0 * fay - 4.0 * q1 * faz
+                + bz * q3 * fmx
+                + (bx * q2 + bz * q0) * fmy
+                + (bx * q3 - 2.0 * bz * q1) * fmz,
+            -2.0 * q0 * fax + 2.0 * q3 * fay - 4.0 * q2 * faz
+                + (-2.0 * bx * q2 - bz * q0) * fmx
+                + (bx * q1 + bz * q3) * fmy
+                + (bx * q0 - 2.0 * bz * q2) * fmz,
+            2.0 * q1 * fax
+                + 2.0 * q2 * fay
+                + (-2.0 * bx * q3 + bz * q1) * fmx
+                + (-bx * q0 + bz * q2) * fmy
+                + bx * q1 * fmz,
+        ]
+    }
+}
+
+/// The magnetometer of the MPU9250 has its x and y axes swapped and its z axis reversed compared
+/// to the accelerometer and the gyroscope
+fn align_mag([x, y, z]: [f32; 3]) -> [f32; 3] {
+    [y, x, -z]
+}
+
+/// Unit vector in the direction of `vector`, `None` if it is zero
+fn normalized(vector: [f32; 3]) -> Option<[f32; 3]> {
+    let norm = vector.iter().map(|v| v * v).sum::<f32>().sqrt();
+    (norm > 0.0).then(|| vector.map(|v| v / norm))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::f32::consts::FRAC_PI_2;
+
+    /// Sample period of the IMU in the tests, 100 Hz
+    const DT_S: f32 = 0.01;
+    const GRAVITY: [f32; 3] = [0.0, 0.0, 9.81];
+    /// Field of the earth seen by the magnetometer with the robot flat and facing north, in µT
+    const NORTH: [f32; 3] = [0.0, 20.0, 40.0];
+
+    fn sample(gyro: [f32; 3], accel: [f32; 3], mag: [f32; 3]) -> MargMeasurements<[f32; 3]> {
+        MargMeasurements {
+            accel,
+            gyro,
+            mag,
+            temp: 25.0,
+        }
+    }
+
+    fn run(
+        filter: &mut Madgwick,
+        sample: &MargMeasurements<[f32; 3]>,
+        seconds: f32,
+    ) -> Orientation {
+        let mut orientation = Orientation::from(Quaternion::IDENTITY);
+        for _ in 0..(seconds / DT_S) as usize {
+            orientation = filter.update(sample, DT_S);
+        }
+        orientation
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn still_and_flat_stays_level() {
+        let mut filter = Madgwick::new(AhrsConfig::default());
+        let orientation = run(&mut filter, &sample([0.0; 3], GRAVITY, NORTH), 5.0);
+        assert_close(orientation.yaw_rad, 0.0, 1e-3);
+        assert_close(orientation.pitch_rad, 0.0, 1e-3);
+        assert_close(orientation.roll_rad, 0.0, 1e-3);
+    }
+
+    #[test]
+    fn gyroscope_alone_is_integrated() {
+        let mut filter = Madgwick::new(AhrsConfig::default());
+        // A quarter turn to the left in one second, without accelerometer nor magnetometer
+        let orientation = run(
+            &mut filter,
+            &sample([0.0, 0.0, FRAC_PI_2], [0.0; 3], [0.0; 3]),
+            1.0,
+        );
+        assert_close(orientation.yaw_rad, FRAC_PI_2, 0.01);
+        assert_close(orientation.pitch_rad, 0.0, 1e-3);
+    }
+
+    #[test]
+    fn accelerometer_corrects_the_tilt() {
+        let mut filter = Madgwick::new(AhrsConfig::default());
+        let (sin, cos) = 0.3f32.sin_cos();
+        let tilted = sample([0.0; 3], [0.0, 9.81 * sin, 9.81 * cos], [0.0; 3]);
+        let orientation = run(&mut filter, &tilted, 10.0);
+        assert_close(orientation.roll_rad.abs(), 0.3, 0.01);
+        assert_close(orientation.pitch_rad, 0.0, 0.01);
+    }
+
+    #[test]
+    fn magnetometer_holds_the_yaw_against_a_gyroscope_bias() {
+        let biased = [0.0, 0.0, 0.01];
+        let mut without_mag = Madgwick::new(AhrsConfig::default());
+        let drifted = run(&mut without_mag, &sample(biased, GRAVITY, [0.0; 3]), 60.0);
+        assert_close(drifted.yaw_rad, 0.6, 0.01);
+
+        let mut with_mag = Madgwick::new(AhrsConfig::default());
+        let held = run(&mut with_mag, &sample(biased, GRAVITY, NORTH), 60.0);
+        assert_close(held.yaw_rad, 0.0, 0.1);
+    }
+}
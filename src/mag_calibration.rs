@@ -0,0 +1,325 @@
+use core::f32::consts::PI;
+use defmt::Format;
+use embassy_time::Duration;
+#[cfg_attr(test, allow(unused_imports))]
+use micromath::F32Ext;
+use mpu9250::MargMeasurements;
+
+/// Number of samples kept for the fit, older ones are decimated when it is reached
+const MAX_SAMPLES: usize = 128;
+/// Fewer samples than this are not enough to fit anything
+const MIN_SAMPLES: usize = 16;
+/// Headings the horizontal plane is divided into to check that the spin covered all of them
+const SECTORS: usize = 12;
+
+/// Hard and soft-iron correction of the magnetometer, applied by the IMU task to every sample.
+///
+/// The field is in the unit of [`MargMeasurements::mag`].
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct MagCalibration {
+    /// Field of the robot itself, the hard iron, subtracted first
+    pub offset: [f32; 3],
+    /// Scale of each axis making the field of the earth a sphere again, the soft iron
+    pub scale: [f32; 3],
+    /// When the calibration is too poor to trust the magnetometer, it is published as zeros,
+    /// which the orientation filters take as no magnetometer
+    pub enabled: bool,
+}
+
+impl MagCalibration {
+    /// No correction, the magnetometer is published as the chip reports it
+    pub const NONE: MagCalibration = MagCalibration {
+        offset: [0.0; 3],
+        scale: [1.0; 3],
+        enabled: true,
+    };
+
+    /// Corrects the magnetometer of a sample
+    pub fn correct(&self, mut sample: MargMeasurements<[f32; 3]>) -> MargMeasurements<[f32; 3]> {
+        sample.mag = if self.enabled {
+            core::array::from_fn(|axis| (sample.mag[axis] - self.offset[axis]) * self.scale[axis])
+        } else {
+            [0.0; 3]
+        };
+        sample
+    }
+}
+
+impl Default for MagCalibration {
+    fn default() -> Self {
+        Self::NONE
+    }
+}
+
+/// Settings of the magnetometer calibration
+#[derive(Debug, Clone, Copy, Format)]
+pub struct MagCalibrationConfig {
+    /// Time during which samples are collected, long enough for at least one full turn
+    pub duration: Duration,
+    /// Fraction of the headings that must have been seen for the magnetometer to stay enabled
+    pub min_coverage: f32,
+    /// Largest standard deviation of the corrected field strength, relative to its mean, for the
+    /// magnetometer to stay enabled
+    pub max_spread: f32,
+}
+
+impl Default for MagCalibrationConfig {
+    fn default() -> Self {
+        Self {
+            duration: Duration::from_secs(10),
+            min_coverage: 0.9,
+            max_spread: 0.1,
+        }
+    }
+}
+
+/// How well the corrected samples of a calibration lie on a sphere
+#[derive(Debug, Clone, Copy, Format)]
+pub struct MagQuality {
+    /// Fraction of the headings seen during the spin
+    pub coverage: f32,
+    /// Standard deviation of the corrected field strength, relative to its mean
+    pub spread: f32,
+}
+
+impl MagQuality {
+    pub fn is_good(&self, config: &MagCalibrationConfig) -> bool {
+        self.coverage >= config.min_coverage && self.spread <= config.max_spread
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum MagCalibrationError {
+    /// The IMU already has the maximum number of subscribers
+    NoSubscriber,
+    /// The IMU stopped publishing
+    Timeout,
+    TooFewSamples,
+}
+
+/// Fits the hard and soft-iron correction to the samples collected while the robot spins.
+///
+/// Each axis is fitted from the extremes of the field along it. An axis that barely changed, like
+/// the vertical one during a flat spin, is left uncorrected.
+pub struct MagCalibrator {
+    samples: heapless::Vec<[f32; 3], MAX_SAMPLES>,
+    /// One sample in `stride` is kept
+    stride: u32,
+    skipped: u32,
+}
+
+impl MagCalibrator {
+    pub fn new() -> Self {
+        Self {
+            samples: heapless::Vec::new(),
+            stride: 1,
+            skipped: 0,
+        }
+    }
+
+    /// Adds a raw magnetometer sample
+    pub fn add(&mut self, mag: [f32; 3]) {
+        self.skipped += 1;
+        if self.skipped < self.stride {
+            return;
+        }
+        self.skipped = 0;
+        if self.samples.is_full() {
+            // Keep every other sample, and from now on half as many
+            let mut index = 0;
+            self.samples.retain(|_| {
+                index += 1;
+                index % 2 == 1
+            });
+            self.stride *= 2;
+        }
+        let _ = self.samples.push(mag);
+    }
+
+    /// Fits the calibration, disabled if its quality is not good enough for `config`
+    pub fn fit(
+        &self,
+        config: &MagCalibrationConfig,
+    ) -> Result<(MagCalibration, MagQuality), MagCalibrationError> {
+        if self.samples.len() < MIN_SAMPLES {
+            return Err(MagCalibrationError::TooFewSamples);
+        }
+        let mut min = [f32::INFINITY; 3];
+        let mut max = [f32::NEG_INFINITY; 3];
+        for sample in self.samples.iter() {
+            for axis in 0..3 {
+                min[axis] = min[axis].min(sample[axis]);
+                max[axis] = max[axis].max(sample[axis]);
+            }
+        }
+        let radius: [f32; 3] = core::array::from_fn(|axis| (max[axis] - min[axis]) / 2.0);
+        let largest = radius.iter().copied().fold(0.0, f32::max);
+        let fitted = radius.map(|radius| radius > 0.0 && radius >= largest / 2.0);
+        let fitted_count = fitted.iter().filter(|fitted| **fitted).count();
+        let mean_radius = (0..3)
+            .filter(|axis| fitted[*axis])
+            .map(|axis| radius[axis])
+            .sum::<f32>()
+            / fitted_count.max(1) as f32;
+
+        let mut calibration = MagCalibration::NONE;
+        for axis in (0..3).filter(|axis| fitted[*axis]) {
+            calibration.offset[axis] = (min[axis] + max[axis]) / 2.0;
+            calibration.scale[axis] = mean_radius / radius[axis];
+        }
+        let quality = self.quality(&calibration, &radius, &fitted);
+        calibration.enabled = fitted_count >= 2 && quality.is_good(config);
+        Ok((calibration, quality))
+    }
+
+    /// Coverage of the two axes that changed most, and spread of the fitted axes
+    fn quality(
+        &self,
+        calibration: &MagCalibration,
+        radius: &[f32; 3],
+        fitted: &[bool; 3],
+    ) -> MagQuality {
+        let mut axes = [0, 1, 2];
+        axes.sort_unstable_by(|a, b| radius[*b].total_cmp(&radius[*a]));
+        let (first, second) = (axes[0], axes[1]);
+
+        let mut seen = [false; SECTORS];
+        let mut sum = 0.0;
+        let mut sum_squares = 0.0;
+        for sample in self.samples.iter() {
+            let corrected: [f32; 3] = core::array::from_fn(|axis| {
+                (sample[axis] - calibration.offset[axis]) * calibration.scale[axis]
+            });
+            let heading = corrected[second].atan2(corrected[first]) + PI;
+            let sector = (heading / (2.0 * PI) * SECTORS as f32) as usize;
+            seen[sector.min(SECTORS - 1)] = true;
+
+            let strength = (0..3)
+                .filter(|axis| fitted[*axis])
+                .map(|axis| corrected[axis] * corrected[axis])
+                .sum::<f32>()
+                .sqrt();
+            sum += strength;
+            sum_squares += strength * strength;
+        }
+        let count = self.samples.len() as f32;
+        let mean = sum / count;
+        let variance = (sum_squares / count - mean * mean).max(0.0);
+        MagQuality {
+            coverage: seen.iter().filter(|seen| **seen).count() as f32 / SECTORS as f32,
+            spread: if mean > 0.0 {
+                variance.sqrt() / mean
+            } else {
+                f32::INFINITY
+            },
+        }
+    }
+}
+
+impl Default for MagCalibrator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Hard iron of the robot in the tests, in µT
+    const OFFSET: [f32; 3] = [12.0, -30.0, 5.0];
+    /// Horizontal field of the earth stretched by the soft iron, in µT
+    const RADIUS: [f32; 2] = [24.0, 16.0];
+    const VERTICAL: f32 = -40.0;
+
+    /// Raw magnetometer during a flat spin of `turns` turns, sampled `count` times
+    fn spin(turns: f32, count: usize) -> MagCalibrator {
+        let mut calibrator = MagCalibrator::new();
+        for index in 0..count {
+            let heading = 2.0 * PI * turns * index as f32 / count as f32;
+            calibrator.add([
+                OFFSET[0] + RADIUS[0] * heading.cos(),
+                OFFSET[1] + RADIUS[1] * heading.sin(),
+                OFFSET[2] + VERTICAL,
+            ]);
+        }
+        calibrator
+    }
+
+    fn assert_close(actual: f32, expected: f32, tolerance: f32) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "{actual} is not within {tolerance} of {expected}"
+        );
+    }
+
+    #[test]
+    fn full_turn_fits_the_horizontal_axes() {
+        let (calibration, quality) = spin(1.0, 500)
+            .fit(&MagCalibrationConfig::default())
+            .unwrap();
+        assert!(calibration.enabled);
+        assert_close(calibration.offset[0], OFFSET[0], 0.5);
+        assert_close(calibration.offset[1], OFFSET[1], 0.5);
+        assert_close(calibration.scale[0], 20.0 / 24.0, 0.02);
+        assert_close(calibration.scale[1], 20.0 / 16.0, 0.02);
+        // The vertical axis didn't change, it is left as it is
+        assert_eq!(calibration.offset[2], 0.0);
+        assert_eq!(calibration.scale[2], 1.0);
+        assert_eq!(quality.coverage, 1.0);
+        assert!(quality.spread < 0.02);
+    }
+
+    #[test]
+    fn corrected_field_is_a_circle() {
+        let (calibration, _) = spin(1.0, 500)
+            .fit(&MagCalibrationConfig::default())
+            .unwrap();
+        for heading in [0.0, 1.0, 2.0, 3.0] {
+            let sample = MargMeasurements {
+                accel: [0.0; 3],
+                gyro: [0.0; 3],
+                mag: [
+                    OFFSET[0] + RADIUS[0] * heading.cos(),
+                    OFFSET[1] + RADIUS[1] * heading.sin(),
+                    0.0,
+                ],
+                temp: 25.0,
+            };
+            let [x, y, _] = calibration.correct(sample).mag;
+            assert_close((x * x + y * y).sqrt(), 20.0, 0.5);
+        }
+    }
+
+    #[test]
+    fn half_turn_disables_the_magnetometer() {
+        let (calibration, quality) = spin(0.5, 200)
+            .fit(&MagCalibrationConfig::default())
+            .unwrap();
+        assert!(!calibration.enabled);
+        assert!(quality.coverage < 0.9);
+        let sample = MargMeasurements {
+            accel: [0.0; 3],
+            gyro: [0.0; 3],
+            mag: [10.0, 20.0, 30.0],
+            temp: 25.0,
+        };
+        assert_eq!(calibration.correct(sample).mag, [0.0; 3]);
+    }
+
+    #[test]
+    fn long_spin_is_decimated() {
+        let calibrator = spin(3.0, 2000);
+        assert!(calibrator.samples.len() <= MAX_SAMPLES);
+        assert!(calibrator.samples.len() > MAX_SAMPLES / 2);
+        assert_eq!(calibrator.stride, 16);
+    }
+
+    #[test]
+    fn too_few_samples() {
+        assert!(matches!(
+            spin(1.0, MIN_SAMPLES - 1).fit(&MagCalibrationConfig::default()),
+            Err(MagCalibrationError::TooFewSamples)
+        ));
+    }
+}
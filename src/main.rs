@@ -2,30 +2,82 @@
 #![no_main]
 extern crate alloc;
 
+mod flash;
+mod i2c_bus;
 mod i2c_devices;
 mod sensor;
+mod spi_devices;
 
-use crate::i2c_devices::init_i2c_devices;
-use crate::sensor::mpu9250::Mpu9250Sensor;
-use crate::sensor::vl53lxx::vl53l0x::VL53L0XSensor;
-use alloc::vec;
-use alloc::vec::Vec;
+use crate::flash::SharedFlash;
+use crate::i2c_devices::{I2C1_SENSORS, I2c1Sensor, init_i2c_devices};
+use crate::sensor::ahrs::{AhrsConfig, Orientation, start_orientation};
+use crate::sensor::channel::SensorState;
+use crate::sensor::distance::{DistanceReading, SensorPosition};
+use crate::sensor::frame::{SensorFrame, start_frames};
+use crate::sensor::health::health_report_task;
+use crate::sensor::mpu9250::mag_calibration::{
+    MagCalibrationConfig, MagCalibrationStore, mag_calibration_task, request_mag_calibration,
+};
+use crate::sensor::mpu9250::{ImuConfig, Mpu9250Config, Mpu9250Sensor};
+use crate::sensor::vl53lxx::bringup::SensorSlot;
+use crate::sensor::vl53lxx::calibration::{
+    CalibrationStore, CalibrationTarget, calibration_task, request_calibration,
+};
+use crate::sensor::vl53lxx::recovery::RecoveryPolicy;
+use crate::sensor::vl53lxx::scan::{ZoneScan, wall_estimate_task};
+use crate::sensor::vl53lxx::{ChipKind, DistanceMode, RangingConfig, Roi, TimingConfig, ToFConfig};
+use crate::sensor::walls::{CellThresholds, wall_detection_task};
+use crate::spi_devices::{Spi1Bus, init_spi_devices};
 use defmt::*;
 use defmt_rtt as _;
 use embassy_executor::Spawner;
 use embassy_stm32::exti::{self, ExtiInput};
+use embassy_stm32::flash::Flash;
 use embassy_stm32::gpio::{Level, Output, Pull, Speed};
+use embassy_stm32::i2c;
 use embassy_stm32::peripherals::I2C1;
 use embassy_stm32::{bind_interrupts, interrupt};
-use embassy_stm32::{i2c, spi};
+use embassy_time::{Duration, with_timeout};
 use embedded_alloc::LlffHeap as Heap;
+use micromouse::filter::{FilterChain, FilterStage};
 use panic_probe as _;
-use sensor::vl53lxx::vl53l1x::VL53L1XSensor;
+use mpu9250::MargMeasurements;
+use sensor::vl53lxx;
 
 #[global_allocator]
 static HEAP: Heap = Heap::empty();
-const HEAP_SIZE: usize = // Add all big structs here !
-    size_of::<VL53L0XSensor>() + size_of::<VL53L1XSensor>() + size_of::<Mpu9250Sensor>() + 500;
+/// Only the running sensors, moved there by [`sensor::start_on_heap`], and the state each sensor
+/// shares with its handles are allocated. The other collections have a fixed capacity.
+const HEAP_SIZE: usize = I2C1_SENSORS
+    * (size_of::<I2c1Sensor>() + size_of::<SensorState<DistanceReading, ToFConfig>>())
+    + size_of::<Mpu9250Sensor<Spi1Bus, Output<'static>>>()
+    + size_of::<SensorState<MargMeasurements<[f32; 3]>, ImuConfig>>()
+    + size_of::<SensorState<SensorFrame>>()
+    + size_of::<SensorState<Orientation>>()
+    // Headers of the blocks and alignment
+    + 500;
+
+/// Readings of the front sensor older than this are not used to estimate the wall in front
+const WALL_ESTIMATE_MAX_AGE: Duration = Duration::from_millis(300);
+
+/// Time between two frames of sensor readings
+const FRAME_PERIOD: Duration = Duration::from_millis(20);
+/// Readings older than this are flagged stale in the frames, a bit more than the slowest sensor
+/// period so that a single late reading doesn't make a sensor stale
+const FRAME_MAX_AGE: Duration = Duration::from_millis(200);
+
+/// Time between two summaries of the health of the sensors
+const HEALTH_REPORT_PERIOD: Duration = Duration::from_secs(10);
+
+/// Offset of the last 128 KiB sector of the STM32F446RE flash, reserved for the ToF calibration
+const CALIBRATION_FLASH_OFFSET: u32 = 0x6_0000;
+/// Offset of the sector before it, reserved for the magnetometer calibration. memory.x keeps the
+/// firmware below it.
+const MAG_CALIBRATION_FLASH_OFFSET: u32 = 0x4_0000;
+
+/// Holding the button longer than this starts the magnetometer calibration instead of the short
+/// press actions
+const LONG_PRESS: Duration = Duration::from_secs(2);
 
 bind_interrupts!(
     struct Irqs {
@@ -47,7 +99,12 @@ async fn main(mut spawner: Spawner) {
 
     let p = embassy_stm32::init(Default::default());
 
-    init_i2c_devices(
+    let flash = SharedFlash::new(Flash::new_blocking(p.FLASH));
+    let calibrations = CalibrationStore::load(flash, CALIBRATION_FLASH_OFFSET);
+    let mag_calibrations = MagCalibrationStore::load(flash, MAG_CALIBRATION_FLASH_OFFSET);
+
+    let front_scan = ZoneScan::halves(8);
+    let distance_sensors = init_i2c_devices(
         &mut spawner,
         p.I2C1,
         p.PB8,
@@ -55,77 +112,119 @@ async fn main(mut spawner: Spawner) {
         p.DMA1_CH6,
         p.DMA1_CH0,
         Irqs,
-        vec![
-            Output::new(p.PC9, Level::Low, Speed::Low),
-            Output::new(p.PC8, Level::Low, Speed::Low),
-        ],
-        vec![
-            ExtiInput::new(p.PA0, p.EXTI0, Pull::None, Irqs),
-            ExtiInput::new(p.PA1, p.EXTI1, Pull::None, Irqs),
+        [
+            SensorSlot {
+                position: SensorPosition::Left,
+                chip: ChipKind::VL53L0X,
+                config: vl53lxx::Config {
+                    timing_config: TimingConfig::default(),
+                    ranging_config: RangingConfig::default(),
+                    address: 0x30,
+                    xshut_pin: Output::new(p.PC9, Level::Low, Speed::Low),
+                    gpio_interrupt: ExtiInput::new(p.PA0, p.EXTI0, Pull::None, Irqs),
+                    recovery_policy: RecoveryPolicy::default(),
+                    // No sigma to reject outliers with, smooth the noisier VL53L0X instead
+                    filter: FilterChain::new(&[FilterStage::median(3), FilterStage::ema(0.5)]),
+                    calibration: calibrations.get(SensorPosition::Left).unwrap_or_default(),
+                },
+            },
+            SensorSlot {
+                position: SensorPosition::Front,
+                chip: ChipKind::VL53L1X,
+                config: vl53lxx::Config {
+                    timing_config: TimingConfig::default(),
+                    // Only the wall right in front matters, and it is always close. Its two halves
+                    // are measured in turn to get the angle of the wall.
+                    ranging_config: RangingConfig {
+                        distance_mode: DistanceMode::Short,
+                        roi: Roi::centered(8, 8),
+                        scan: Some(front_scan),
+                        ..RangingConfig::default()
+                    },
+                    address: 0x31,
+                    xshut_pin: Output::new(p.PC8, Level::Low, Speed::Low),
+                    gpio_interrupt: ExtiInput::new(p.PA1, p.EXTI1, Pull::None, Irqs),
+                    recovery_policy: RecoveryPolicy::default(),
+                    filter: FilterChain::new(&[
+                        FilterStage::reject_outliers(3.0, 5),
+                        FilterStage::median(3),
+                    ]),
+                    calibration: calibrations.get(SensorPosition::Front).unwrap_or_default(),
+                },
+            },
         ],
     )
-        .await;
-
-    /*
-    info!("Configuring SPI...");
-    let mut spi_config = spi::Config::default();
-    spi_config.frequency = Hertz::khz(100); // Start with 100kHz for maximum reliability
-    // MPU9250 library requires Mode 3 (CPOL=1, CPHA=1)
-    // This matches mpu9250::MODE constant: IdleHigh, CaptureOnSecondTransition
-    spi_config.mode = spi::Mode {
-        polarity: spi::Polarity::IdleHigh,
-        phase: spi::Phase::CaptureOnSecondTransition,
-    };
+    .await;
+    info!("{} distance sensors running", distance_sensors.available.len());
+    for position in distance_sensors.unavailable.iter() {
+        warn!("Running without the {} distance sensor", position);
+    }
+    spawner
+        .spawn(health_report_task(HEALTH_REPORT_PERIOD))
+        .unwrap();
+    let frames = start_frames(&mut spawner, FRAME_PERIOD, FRAME_MAX_AGE).unwrap();
+    spawner
+        .spawn(wall_detection_task(frames, CellThresholds::default()))
+        .unwrap();
+    spawner
+        .spawn(wall_estimate_task(
+            SensorPosition::Front,
+            front_scan,
+            WALL_ESTIMATE_MAX_AGE,
+        ))
+        .unwrap();
+    spawner
+        .spawn(calibration_task(
+            calibrations,
+            [
+                // The VL53L0X has no cover glass
+                CalibrationTarget {
+                    position: SensorPosition::Left,
+                    offset_distance_mm: 100,
+                    crosstalk_distance_mm: None,
+                },
+                CalibrationTarget {
+                    position: SensorPosition::Front,
+                    offset_distance_mm: 100,
+                    crosstalk_distance_mm: Some(600),
+                },
+            ]
+            .into_iter()
+            .collect(),
+        ))
+        .unwrap();
 
-    info!("Creating SPI with MISO pull-down...");
-    let spi = Spi::new(
-        p.SPI1,     //
-        p.PB3,      // SCK
-        p.PB5,      // MOSI / SDA
-        p.PB4,      // MISO (with internal pull-down to prevent floating)
-        p.DMA2_CH3, //
-        p.DMA2_CH2, //
-        spi_config,
-    );
-
-    info!("Setting up chip select (CS)...");
-    let mut chip_select = Output::new(p.PC6, Level::High, Speed::Medium);
-    let interrupt = ExtiInput::new(p.PA2, p.EXTI2, Pull::None, Irqs);
-
-    // MPU9250 requires CS to be high during power-on to enable SPI mode
-    // Pulse CS to ensure the chip recognizes SPI mode
-    info!("Pulsing CS to enable SPI mode...");
-    chip_select.set_high();
-    embassy_time::Timer::after(embassy_time::Duration::from_millis(50)).await;
-    chip_select.set_low();
-    embassy_time::Timer::after(embassy_time::Duration::from_micros(100)).await;
-    chip_select.set_high();
-    embassy_time::Timer::after(embassy_time::Duration::from_millis(10)).await;
-
-    info!("Initializing MPU9250 IMU...");
-
-    let imu =
-        Mpu9250Sensor::init_new(spi, chip_select, interrupt);
-    let imu = match imu {
-        Ok(s) => {
-            info!("IMU initialized successfully");
-            Box::leak(Box::new(s))
-        }
-        Err(e) => {
-            error!("Failed to initialize IMU: {}", e);
-            core::panic!("Sensor initialization failed");
+    match init_spi_devices(
+        &mut spawner,
+        p.SPI1,
+        p.PB3,
+        p.PB5,
+        p.PB4,
+        p.DMA2_CH3,
+        p.DMA2_CH2,
+        p.PC6,
+        p.PA2,
+        p.EXTI2,
+        Irqs,
+        Mpu9250Config {
+            mag_calibration: mag_calibrations.get().unwrap_or_default(),
+            ..Mpu9250Config::default()
+        },
+    )
+    .await
+    {
+        Ok(imu) => {
+            info!("IMU running");
+            start_orientation(&mut spawner, imu, AhrsConfig::default()).unwrap();
         }
-    };
-    */
-
-    // imu.start_continuous_measurement(&mut spawner, &|data| {
-    //     info!(
-    //         "New IMU data: Accel: {:?}, Gyro: {:?}, Mag: {:?}, Temp: {}",
-    //         data.accel, data.gyro, data.mag, data.temp
-    //     );
-    // })
-    // .await
-    // .unwrap();
+        Err(e) => error!("Running without the IMU: {}", e),
+    }
+    spawner
+        .spawn(mag_calibration_task(
+            mag_calibrations,
+            MagCalibrationConfig::default(),
+        ))
+        .unwrap();
 
     let user_button = ExtiInput::new(p.PC13, p.EXTI13, Pull::None, Irqs);
     let led = Output::new(p.PA5, Level::Low, Speed::Medium);
@@ -139,11 +238,22 @@ async fn button_task(mut button: ExtiInput<'_>, mut led: Output<'_>) {
         led.toggle();
     };
 
-    let mut button_actions: Vec<&mut dyn FnMut()> = Vec::new();
-    button_actions.push(&mut toggle_led);
+    // Place the calibration targets and press the button to calibrate the distance sensors
+    let mut calibrate = request_calibration;
+
+    let mut button_actions: [&mut dyn FnMut(); 2] = [&mut toggle_led, &mut calibrate];
 
     loop {
-        button.wait_for_any_edge().await;
+        button.wait_for_falling_edge().await;
+        // Spin the robot after a long press to calibrate the magnetometer
+        if with_timeout(LONG_PRESS, button.wait_for_rising_edge())
+            .await
+            .is_err()
+        {
+            request_mag_calibration();
+            button.wait_for_rising_edge().await;
+            continue;
+        }
         for action in button_actions.iter_mut() {
             action()
         }
@@ -0,0 +1,461 @@
+use core::f32::consts::PI;
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+use embedded_hal_async::spi::SpiBus as AsyncSpiBus;
+use mpu9250::MargMeasurements;
+
+const SMPLRT_DIV: u8 = 0x19;
+const CONFIG: u8 = 0x1A;
+const GYRO_CONFIG: u8 = 0x1B;
+const ACCEL_CONFIG: u8 = 0x1C;
+const ACCEL_CONFIG_2: u8 = 0x1D;
+const FIFO_EN: u8 = 0x23;
+const I2C_SLV0_CTRL: u8 = 0x27;
+const INT_ENABLE: u8 = 0x38;
+const INT_STATUS: u8 = 0x3A;
+const USER_CTRL: u8 = 0x6A;
+const FIFO_COUNT_H: u8 = 0x72;
+const FIFO_R_W: u8 = 0x74;
+
+/// Set in the register address to read it
+const READ: u8 = 0x80;
+/// CONFIG: drop the new samples instead of the oldest ones when the FIFO is full, so that it
+/// stays aligned on samples
+const FIFO_MODE_KEEP_OLDEST: u8 = 0x40;
+/// FIFO_EN: temperature, gyroscope, accelerometer and the data of the I2C slave 0
+const FIFO_EN_TEMP: u8 = 0x80;
+const FIFO_EN_GYRO: u8 = 0x70;
+const FIFO_EN_ACCEL: u8 = 0x08;
+const FIFO_EN_SLV0: u8 = 0x01;
+const I2C_SLV_EN: u8 = 0x80;
+const I2C_SLV_LENGTH: u8 = 0x0F;
+/// INT_ENABLE and INT_STATUS
+const FIFO_OVERFLOW: u8 = 0x10;
+/// USER_CTRL
+const USER_CTRL_FIFO_EN: u8 = 0x40;
+const USER_CTRL_FIFO_RST: u8 = 0x04;
+const FIFO_COUNT_MASK: u16 = 0x1FFF;
+
+/// Bytes the FIFO can hold
+pub const FIFO_SIZE: usize = 512;
+/// Accelerometer, temperature and gyroscope bytes of a sample in the FIFO
+const MOTION_BYTES: usize = 14;
+
+/// Full scale of ±4 g
+const ACCEL_FS_4G: u8 = 0x08;
+const ACCEL_M_S2_PER_LSB: f32 = 9.80665 / 8192.0;
+/// Full scale of ±1000 °/s
+const GYRO_FS_1000DPS: u8 = 0x10;
+const GYRO_RAD_S_PER_LSB: f32 = PI / 180.0 / 32.8;
+const TEMP_LSB_PER_DEG_C: f32 = 333.87;
+const TEMP_OFFSET_DEG_C: f32 = 21.0;
+/// Resolution of the AK8963 in 16-bit mode, before its factory sensitivity adjustment
+const MAG_UT_PER_LSB: f32 = 0.15;
+
+/// Internal sample rate of the gyroscope and the accelerometer when their low-pass filter is on
+const INTERNAL_RATE_HZ: u64 = 1000;
+
+/// Bandwidth of the digital low-pass filters. The accelerometer ones are a bit wider than the
+/// gyroscope ones they are named after.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum DlpfBandwidth {
+    Hz184 = 1,
+    Hz92 = 2,
+    Hz41 = 3,
+    Hz20 = 4,
+    Hz10 = 5,
+    Hz5 = 6,
+}
+
+/// Rate and filtering of the samples of the IMU
+#[derive(Debug, Clone, Copy, Format)]
+pub struct SamplingConfig {
+    /// The sample rate is 1 kHz / (1 + divider)
+    pub sample_rate_divider: u8,
+    pub gyro_bandwidth: DlpfBandwidth,
+    pub accel_bandwidth: DlpfBandwidth,
+    /// Time between two reads of the FIFO. It must stay well below the time the FIFO takes to
+    /// fill up, 24 samples with the magnetometer.
+    pub read_period: Duration,
+}
+
+impl SamplingConfig {
+    pub fn sample_period(&self) -> Duration {
+        Duration::from_micros(1_000_000 * (1 + self.sample_rate_divider as u64) / INTERNAL_RATE_HZ)
+    }
+}
+
+impl Default for SamplingConfig {
+    /// The gyroscope at 1 kHz for the control loop
+    fn default() -> Self {
+        Self {
+            sample_rate_divider: 0,
+            gyro_bandwidth: DlpfBandwidth::Hz184,
+            accel_bandwidth: DlpfBandwidth::Hz41,
+            read_period: Duration::from_millis(4),
+        }
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum RegisterError<S, C> {
+    Spi(S),
+    ChipSelect(C),
+}
+
+/// Samples read from the FIFO at once, see [`Fifo::sample`]
+pub struct FifoBatch {
+    pub samples: usize,
+    /// When the FIFO was read, the time of its newest sample
+    pub timestamp: Instant,
+    /// Samples were lost because the FIFO was full, see [`Fifo::read_batch`]
+    pub overflow: bool,
+}
+
+/// FIFO of the MPU9250, read in bursts over DMA.
+///
+/// It takes over the bus once the driver initialized the chip, including the I2C master reading
+/// the magnetometer into the data of the I2C slave 0, which the FIFO also buffers.
+pub struct Fifo<SPI, NCS> {
+    spi: SPI,
+    ncs: NCS,
+    /// Bytes of the magnetometer in each sample, 0 if the slave 0 is not reading it
+    mag_bytes: usize,
+    /// Factory sensitivity adjustment of each axis of the magnetometer
+    mag_sensitivity: [f32; 3],
+    sample_period: Duration,
+    buffer: [u8; FIFO_SIZE],
+}
+
+impl<SPI: SpiBus + AsyncSpiBus, NCS: OutputPin> Fifo<SPI, NCS> {
+    /// `mag_sensitivity` is the factory sensitivity adjustment of the magnetometer, read by the
+    /// driver from the fuse ROM of the AK8963. It is applied to every sample so that the field is
+    /// in the same unit as the one of the driver.
+    pub fn new(
+        spi: SPI,
+        ncs: NCS,
+        config: &SamplingConfig,
+        mag_sensitivity: [f32; 3],
+    ) -> Result<Self, RegisterError<SPI::Error, NCS::Error>> {
+        let mut fifo = Self {
+            spi,
+            ncs,
+            mag_bytes: 0,
+            mag_sensitivity,
+            sample_period: config.sample_period(),
+            buffer: [0; FIFO_SIZE],
+        };
+        fifo.configure(config)?;
+        Ok(fifo)
+    }
+
+    /// Applies `config` and starts buffering from an empty FIFO
+    pub fn configure(
+        &mut self,
+        config: &SamplingConfig,
+    ) -> Result<(), RegisterError<SPI::Error, NCS::Error>> {
+        self.write(FIFO_EN, 0)?;
+        self.write(SMPLRT_DIV, config.sample_rate_divider)?;
+        self.write(CONFIG, FIFO_MODE_KEEP_OLDEST | config.gyro_bandwidth as u8)?;
+        // Clearing FCHOICE_B enables the low-pass filters
+        self.write(GYRO_CONFIG, GYRO_FS_1000DPS)?;
+        self.write(ACCEL_CONFIG, ACCEL_FS_4G)?;
+        self.write(ACCEL_CONFIG_2, config.accel_bandwidth as u8)?;
+        self.write(INT_ENABLE, FIFO_OVERFLOW)?;
+
+        let slave = self.read(I2C_SLV0_CTRL)?;
+        self.mag_bytes = if slave & I2C_SLV_EN != 0 && (slave & I2C_SLV_LENGTH) >= 6 {
+            (slave & I2C_SLV_LENGTH) as usize
+        } else {
+            0
+        };
+        self.sample_period = config.sample_period();
+        self.reset()?;
+        let slave_0 = if self.mag_bytes > 0 { FIFO_EN_SLV0 } else { 0 };
+        self.write(
+            FIFO_EN,
+            FIFO_EN_TEMP | FIFO_EN_GYRO | FIFO_EN_ACCEL | slave_0,
+        )
+    }
+
+    /// Empties the FIFO
+    pub fn reset(&mut self) -> Result<(), RegisterError<SPI::Error, NCS::Error>> {
+        // Keep the I2C master reading the magnetometer running
+        let user_ctrl = self.read(USER_CTRL)?;
+        self.write(
+            USER_CTRL,
+            user_ctrl | USER_CTRL_FIFO_EN | USER_CTRL_FIFO_RST,
+        )
+    }
+
+    /// Reads every complete sample buffered since the previous read.
+    ///
+    /// After an overflow, the FIFO is emptied and nothing is returned: the samples left in it are
+    /// older than the ones that were dropped, so their time is unknown.
+    pub async fn read_batch(&mut self) -> Result<FifoBatch, RegisterError<SPI::Error, NCS::Error>> {
+        let mut status = [0; 1];
+        read_burst(&mut self.spi, &mut self.ncs, INT_STATUS, &mut status).await?;
+        if status[0] & FIFO_OVERFLOW != 0 {
+            self.reset()?;
+            return Ok(FifoBatch {
+                samples: 0,
+                timestamp: Instant::now(),
+                overflow: true,
+            });
+        }
+        let mut count = [0; 2];
+        read_burst(&mut self.spi, &mut self.ncs, FIFO_COUNT_H, &mut count).await?;
+        let timestamp = Instant::now();
+        let count = (u16::from_be_bytes(count) & FIFO_COUNT_MASK) as usize;
+        let samples = count.min(FIFO_SIZE) / self.sample_size();
+        let bytes = samples * self.sample_size();
+        read_burst(
+            &mut self.spi,
+            &mut self.ncs,
+            FIFO_R_W,
+            &mut self.buffer[..bytes],
+        )
+        .await?;
+        Ok(FifoBatch {
+            samples,
+            timestamp,
+            overflow: false,
+        })
+    }
+
+    /// Sample `index` of `batch`, 0 being the oldest, with the time it was taken
+    pub fn sample(&self, batch: &FifoBatch, index: usize) -> (MargMeasurements<[f32; 3]>, Instant) {
+        let start = index * self.sample_size();
+        let bytes = &self.buffer[start..start + self.sample_size()];
+        let big_endian = |offset: usize| i16::from_be_bytes([bytes[offset], bytes[offset + 1]]);
+        let little_endian = |offset: usize| i16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let measurements = MargMeasurements {
+            accel: core::array::from_fn(|axis| big_endian(2 * axis) as f32 * ACCEL_M_S2_PER_LSB),
+            temp: big_endian(6) as f32 / TEMP_LSB_PER_DEG_C + TEMP_OFFSET_DEG_C,
+            gyro: core::array::from_fn(|axis| big_endian(8 + 2 * axis) as f32 * GYRO_RAD_S_PER_LSB),
+            mag: if self.mag_bytes > 0 {
+                core::array::from_fn(|axis| {
+                    little_endian(MOTION_BYTES + 2 * axis) as f32
+                        * MAG_UT_PER_LSB
+                        * self.mag_sensitivity[axis]
+                })
+            } else {
+                [0.0; 3]
+            },
+        };
+        let age = self.sample_period * (batch.samples - 1 - index) as u32;
+        (measurements, batch.timestamp - age)
+    }
+
+    fn sample_size(&self) -> usize {
+        MOTION_BYTES + self.mag_bytes
+    }
+
+    fn write(
+        &mut self,
+        register: u8,
+        value: u8,
+    ) -> Result<(), RegisterError<SPI::Error, NCS::Error>> {
+        self.ncs.set_low().map_err(RegisterError::ChipSelect)?;
+        let result = SpiBus::write(&mut self.spi, &[register, value]);
+        self.ncs.set_high().map_err(RegisterError::ChipSelect)?;
+        result.map_err(RegisterError::Spi)
+    }
+
+    fn read(&mut self, register: u8) -> Result<u8, RegisterError<SPI::Error, NCS::Error>> {
+        let mut buffer = [register | READ, 0];
+        self.ncs.set_low().map_err(RegisterError::ChipSelect)?;
+        let result = SpiBus::transfer_in_place(&mut self.spi, &mut buffer);
+        self.ncs.set_high().map_err(RegisterError::ChipSelect)?;
+        result.map_err(RegisterError::Spi)?;
+        Ok(buffer[1])
+    }
+}
+
+/// Reads consecutive registers from `register` over DMA. The FIFO register is read again and again
+/// instead.
+async fn read_burst<SPI: AsyncSpiBus, NCS: OutputPin>(
+    spi: &mut SPI,
+    ncs: &mut NCS,
+    register: u8,
+    buffer: &mut [u8],
+) -> Result<(), RegisterError<SPI::Error, NCS::Error>> {
+    ncs.set_low().map_err(RegisterError::ChipSelect)?;
+    let mut result = spi.write(&[register | READ]).await;
+    if result.is_ok() && !buffer.is_empty() {
+        result = spi.read(buffer).await;
+    }
+    ncs.set_high().map_err(RegisterError::ChipSelect)?;
+    result.map_err(RegisterError::Spi)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use embassy_futures::block_on;
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+    use embedded_hal_mock::eh1::spi::{Mock as SpiMock, Transaction as SpiTransaction};
+
+    /// I2C_SLV0_CTRL set by the driver: slave 0 enabled, reading the 7 bytes of the AK8963 from
+    /// HXL to ST2
+    const SLAVE_0_MAG: u8 = 0x87;
+    /// USER_CTRL set by the driver: I2C master enabled
+    const USER_CTRL_I2C_MST_EN: u8 = 0x20;
+    /// Factory sensitivity adjustment of the magnetometer, as the driver reads it
+    const MAG_SENSITIVITY: [f32; 3] = [1.1, 0.9, 1.0];
+
+    /// Expected transactions on the bus, each one framed by the chip select
+    #[derive(Default)]
+    struct Expectations {
+        spi: Vec<SpiTransaction<u8>>,
+        ncs: Vec<PinTransaction>,
+    }
+
+    impl Expectations {
+        fn select(&mut self, transactions: impl IntoIterator<Item = SpiTransaction<u8>>) {
+            self.ncs.push(PinTransaction::set(State::Low));
+            self.spi.extend(transactions);
+            self.ncs.push(PinTransaction::set(State::High));
+        }
+
+        fn write(&mut self, register: u8, value: u8) {
+            self.select([SpiTransaction::write_vec(vec![register, value])]);
+        }
+
+        fn read(&mut self, register: u8, value: u8) {
+            self.select([SpiTransaction::transfer_in_place(
+                vec![register | READ, 0],
+                vec![0, value],
+            )]);
+        }
+
+        fn read_burst(&mut self, register: u8, values: Vec<u8>) {
+            self.select([
+                SpiTransaction::write_vec(vec![register | READ]),
+                SpiTransaction::read_vec(values),
+            ]);
+        }
+
+        /// Transactions of [`Fifo::reset`]
+        fn reset(&mut self) {
+            self.read(USER_CTRL, USER_CTRL_I2C_MST_EN);
+            self.write(
+                USER_CTRL,
+                USER_CTRL_I2C_MST_EN | USER_CTRL_FIFO_EN | USER_CTRL_FIFO_RST,
+            );
+        }
+
+        /// Transactions of [`Fifo::configure`] with the default config and the magnetometer
+        fn configure(&mut self) {
+            self.write(FIFO_EN, 0);
+            self.write(SMPLRT_DIV, 0);
+            self.write(CONFIG, FIFO_MODE_KEEP_OLDEST | DlpfBandwidth::Hz184 as u8);
+            self.write(GYRO_CONFIG, GYRO_FS_1000DPS);
+            self.write(ACCEL_CONFIG, ACCEL_FS_4G);
+            self.write(ACCEL_CONFIG_2, DlpfBandwidth::Hz41 as u8);
+            self.write(INT_ENABLE, FIFO_OVERFLOW);
+            self.read(I2C_SLV0_CTRL, SLAVE_0_MAG);
+            self.reset();
+            self.write(
+                FIFO_EN,
+                FIFO_EN_TEMP | FIFO_EN_GYRO | FIFO_EN_ACCEL | FIFO_EN_SLV0,
+            );
+        }
+
+        /// Runs `test` on a FIFO over mocks expecting these transactions, then checks that they
+        /// all happened
+        fn check(self, test: impl FnOnce(&mut Fifo<SpiMock<u8>, PinMock>)) {
+            let mut spi = SpiMock::new(&self.spi);
+            let mut ncs = PinMock::new(&self.ncs);
+            let config = SamplingConfig::default();
+            let mut fifo = Fifo::new(spi.clone(), ncs.clone(), &config, MAG_SENSITIVITY).unwrap();
+            test(&mut fifo);
+            spi.done();
+            ncs.done();
+        }
+    }
+
+    /// A sample as the FIFO holds it: accelerometer, temperature and gyroscope in big endian,
+    /// then the magnetometer in little endian and ST2
+    fn sample_bytes(accel: [i16; 3], temp: i16, gyro: [i16; 3], mag: [i16; 3]) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for value in accel.iter().chain([&temp]).chain(gyro.iter()) {
+            bytes.extend(value.to_be_bytes());
+        }
+        for value in mag {
+            bytes.extend(value.to_le_bytes());
+        }
+        bytes.push(0x10);
+        bytes
+    }
+
+    fn assert_close(actual: [f32; 3], expected: [f32; 3]) {
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-3,
+                "{actual} is not close to {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn configures_the_fifo_with_the_magnetometer() {
+        let mut expectations = Expectations::default();
+        expectations.configure();
+        expectations.check(|fifo| assert_eq!(fifo.sample_size(), MOTION_BYTES + 7));
+    }
+
+    #[test]
+    fn reads_and_converts_a_batch() {
+        let still = sample_bytes([0, 0, 8192], 0, [0; 3], [100, -200, 300]);
+        let turning = sample_bytes([4096, 0, 8192], 334, [0, 0, 328], [100, -200, 300]);
+        let batch_bytes = [still, turning].concat();
+
+        let mut expectations = Expectations::default();
+        expectations.configure();
+        expectations.read_burst(INT_STATUS, vec![0x01]);
+        expectations.read_burst(
+            FIFO_COUNT_H,
+            (batch_bytes.len() as u16).to_be_bytes().to_vec(),
+        );
+        expectations.read_burst(FIFO_R_W, batch_bytes);
+        expectations.check(|fifo| {
+            let batch = block_on(fifo.read_batch()).unwrap();
+            assert_eq!(batch.samples, 2);
+            assert!(!batch.overflow);
+
+            let (still, still_timestamp) = fifo.sample(&batch, 0);
+            assert_close(still.accel, [0.0, 0.0, 9.80665]);
+            assert_close(still.gyro, [0.0; 3]);
+            assert_close(still.mag, [16.5, -27.0, 45.0]);
+            assert_eq!(still.temp, TEMP_OFFSET_DEG_C);
+
+            let (turning, turning_timestamp) = fifo.sample(&batch, 1);
+            assert_close(turning.accel, [4.903325, 0.0, 9.80665]);
+            assert_close(turning.gyro, [0.0, 0.0, 10.0f32.to_radians()]);
+            assert!((turning.temp - 22.0).abs() < 0.01);
+            assert_eq!(turning_timestamp, batch.timestamp);
+            assert_eq!(
+                turning_timestamp - still_timestamp,
+                Duration::from_millis(1)
+            );
+        });
+    }
+
+    #[test]
+    fn drops_the_samples_after_an_overflow() {
+        let mut expectations = Expectations::default();
+        expectations.configure();
+        expectations.read_burst(INT_STATUS, vec![FIFO_OVERFLOW]);
+        expectations.reset();
+        expectations.check(|fifo| {
+            let batch = block_on(fifo.read_batch()).unwrap();
+            assert!(batch.overflow);
+            assert_eq!(batch.samples, 0);
+        });
+    }
+}
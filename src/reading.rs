@@ -0,0 +1,54 @@
+use defmt::Format;
+
+/// Where a distance sensor is mounted on the robot
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SensorPosition {
+    Left,
+    FrontLeft,
+    Front,
+    FrontRight,
+    Right,
+}
+
+impl SensorPosition {
+    pub const ALL: [SensorPosition; 5] = [
+        SensorPosition::Left,
+        SensorPosition::FrontLeft,
+        SensorPosition::Front,
+        SensorPosition::FrontRight,
+        SensorPosition::Right,
+    ];
+}
+
+/// How much a [`DistanceReading`] can be trusted, normalized across the ToF chips
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RangeQuality {
+    /// The distance is valid
+    Valid,
+    /// The distance is probably right but less precise than usual (high sigma, clipped range...)
+    Degraded,
+    /// Nothing reflected enough light back, usually meaning there is no wall in range
+    NoTarget,
+    /// The distance must not be used (phase or wrap-around error, hardware failure...)
+    Invalid,
+}
+
+/// A distance measurement, independent of the chip it comes from
+#[derive(Debug, Clone, Copy, Format)]
+pub struct DistanceReading {
+    pub mm: u16,
+    /// Estimated standard deviation of the distance, `None` if the chip doesn't report it
+    pub sigma_mm: Option<f32>,
+    /// Rate of the photons reflected back, in mega counts per second
+    pub signal_rate_mcps: f32,
+    pub quality: RangeQuality,
+    /// Index of the ROI the distance was measured in when the sensor scans several zones
+    pub zone: Option<u8>,
+}
+
+impl DistanceReading {
+    /// Whether the distance can be used, even if it is less precise than usual
+    pub fn is_usable(&self) -> bool {
+        matches!(self.quality, RangeQuality::Valid | RangeQuality::Degraded)
+    }
+}
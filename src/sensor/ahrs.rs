@@ -0,0 +1,61 @@
+use crate::sensor::channel::SensorHandle;
+use crate::sensor::mpu9250::ImuHandle;
+use core::cell::RefCell;
+use defmt::warn;
+use embassy_executor::{SpawnError, Spawner};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{Duration, Instant};
+use micromouse::madgwick::Madgwick;
+pub use micromouse::madgwick::{AhrsConfig, Orientation};
+
+/// Samples further apart than this are not integrated, the IMU was stopped in between
+const MAX_STEP: Duration = Duration::from_millis(100);
+
+/// Handle of the orientation published by [`start_orientation`]
+pub type OrientationHandle = SensorHandle<Orientation>;
+
+static ORIENTATION: Mutex<CriticalSectionRawMutex, RefCell<Option<OrientationHandle>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Returns the handle of the orientation, or `None` if the IMU is not running
+pub fn orientation() -> Option<OrientationHandle> {
+    ORIENTATION.lock(|orientation| *orientation.borrow())
+}
+
+/// Starts estimating the orientation from every sample of `imu`. It is published with the
+/// timestamp of the sample it was updated with.
+pub fn start_orientation(
+    spawner: &mut Spawner,
+    imu: ImuHandle,
+    config: AhrsConfig,
+) -> Result<OrientationHandle, SpawnError> {
+    let handle = OrientationHandle::new();
+    spawner.spawn(orientation_task(imu, handle, config))?;
+    ORIENTATION.lock(|orientation| *orientation.borrow_mut() = Some(handle));
+    Ok(handle)
+}
+
+#[embassy_executor::task]
+async fn orientation_task(imu: ImuHandle, handle: OrientationHandle, config: AhrsConfig) -> ! {
+    let mut subscription = imu.subscribe().unwrap();
+    let mut filter = Madgwick::new(config);
+    let mut previous: Option<Instant> = None;
+    loop {
+        let sample = match subscription.next().await {
+            WaitResult::Message(sample) => sample,
+            WaitResult::Lagged(count) => {
+                warn!("Orientation lagged, {} IMU samples missed", count);
+                continue;
+            }
+        };
+        let step = previous.map(|previous| sample.timestamp.saturating_duration_since(previous));
+        previous = Some(sample.timestamp);
+        let Some(step) = step.filter(|step| *step <= MAX_STEP) else {
+            continue;
+        };
+        let dt_s = step.as_micros() as f32 / 1_000_000.0;
+        handle.publish(filter.update(&sample.measurement, dt_s), sample.timestamp);
+    }
+}
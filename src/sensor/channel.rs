@@ -0,0 +1,230 @@
+use crate::sensor::command::SensorCommand;
+use crate::sensor::health::{HealthEvent, HealthHandle, HealthState};
+use alloc::boxed::Box;
+use core::cell::RefCell;
+use defmt::Format;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::{Channel, TrySendError};
+use embassy_sync::pubsub;
+use embassy_sync::pubsub::{PubSubBehavior, PubSubChannel, Subscriber, WaitResult};
+use embassy_time::{Duration, Instant};
+
+/// Number of measurements buffered per sensor before the slowest subscriber starts lagging
+pub const CHANNEL_CAPACITY: usize = 4;
+/// Maximum number of tasks that can subscribe to the same sensor
+pub const MAX_SUBSCRIBERS: usize = 4;
+/// Number of commands that can be queued for a sensor task before senders have to wait
+pub const COMMAND_CAPACITY: usize = 2;
+
+/// Channel a sensor task publishes its measurements into.
+///
+/// The sensor task never waits for its subscribers: when the buffer is full, the oldest
+/// measurement is overwritten and the subscribers that did not read it get a
+/// [`WaitResult::Lagged`] telling them how many measurements they missed.
+pub type SensorChannel<M> =
+    PubSubChannel<CriticalSectionRawMutex, Timestamped<M>, CHANNEL_CAPACITY, MAX_SUBSCRIBERS, 0>;
+
+/// A measurement together with the time it was taken.
+///
+/// The timestamp is captured when the sensor task wakes up on the data-ready interrupt edge, before
+/// the measurement is read from the bus, so that samples of different sensors can be fused.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct Timestamped<M> {
+    pub measurement: M,
+    pub timestamp: Instant,
+}
+
+impl<M> Timestamped<M> {
+    /// Time elapsed since the measurement was taken
+    pub fn age(&self) -> Duration {
+        self.timestamp.elapsed()
+    }
+
+    /// Whether the measurement is older than `max_age`
+    pub fn is_stale(&self, max_age: Duration) -> bool {
+        self.age() > max_age
+    }
+}
+
+/// Everything a sensor task shares with the rest of the firmware.
+///
+/// The sensor task only ever writes through this struct and never hands out references to its
+/// own fields, so readers in other tasks or in interrupts never alias the task's `&'static mut`.
+///
+/// `C` is the configuration type that can be sent to the sensor task with
+/// [`SensorCommand::Reconfigure`].
+pub struct SensorState<M: Clone + 'static, C: 'static = ()> {
+    channel: SensorChannel<M>,
+    /// Most recent measurement, behind a critical section so it can be read from interrupts
+    latest: Mutex<CriticalSectionRawMutex, RefCell<Option<Timestamped<M>>>>,
+    commands: Channel<CriticalSectionRawMutex, SensorCommand<C>, COMMAND_CAPACITY>,
+    health: HealthState,
+}
+
+/// Cheap, copyable handle to the measurements of a sensor, that can be passed to any task.
+/// It is also used to control the sensor task once it is running.
+pub struct SensorHandle<M: Clone + 'static, C: 'static = ()> {
+    state: &'static SensorState<M, C>,
+}
+
+impl<M: Clone + 'static, C: 'static> Clone for SensorHandle<M, C> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<M: Clone + 'static, C: 'static> Copy for SensorHandle<M, C> {}
+
+impl<M: Clone + 'static, C: 'static> SensorHandle<M, C> {
+    /// Allocates the shared state on the heap. Sensors call this once in their `init_new`.
+    pub(crate) fn new() -> Self {
+        Self {
+            state: Box::leak(Box::new(SensorState {
+                channel: SensorChannel::new(),
+                latest: Mutex::new(RefCell::new(None)),
+                commands: Channel::new(),
+                health: HealthState::new(),
+            })),
+        }
+    }
+
+    /// Registers a new subscriber. It will only receive measurements published after this call.
+    pub fn subscribe(&self) -> Result<Subscription<M>, pubsub::Error> {
+        Ok(Subscription {
+            subscriber: self.state.channel.subscriber()?,
+            missed: 0,
+        })
+    }
+
+    /// Returns a copy of the most recent measurement, or `None` if the sensor did not publish
+    /// anything yet. Safe to call from any task or interrupt.
+    pub fn latest(&self) -> Option<Timestamped<M>> {
+        self.state.latest.lock(|latest| latest.borrow().clone())
+    }
+
+    /// Returns the most recent measurement if it is not older than `max_age`
+    pub fn latest_fresh(&self, max_age: Duration) -> Option<Timestamped<M>> {
+        self.latest().filter(|latest| !latest.is_stale(max_age))
+    }
+
+    /// Statistics about the errors and recoveries of the sensor task
+    pub fn health(&self) -> HealthHandle {
+        HealthHandle::new(&self.state.health)
+    }
+
+    /// Counts an event in the health statistics. Only meant to be called by the sensor task itself.
+    pub(crate) fn record(&self, event: HealthEvent) {
+        self.health().record(event);
+    }
+
+    /// Stores the measurement taken at `timestamp` as the latest one and sends it to every
+    /// subscriber, overwriting the oldest one if the buffer is full.
+    pub(crate) fn publish(&self, measurement: M, timestamp: Instant) {
+        self.publish_with_quality(measurement, timestamp, true);
+    }
+
+    /// Same as [`Self::publish`], for a measurement that may be unusable. Only the usable ones
+    /// count as good in the health statistics.
+    pub(crate) fn publish_with_quality(&self, measurement: M, timestamp: Instant, usable: bool) {
+        let measurement = Timestamped {
+            measurement,
+            timestamp,
+        };
+        self.state.latest.lock(|latest| {
+            *latest.borrow_mut() = Some(measurement.clone());
+        });
+        self.state.channel.publish_immediate(measurement);
+        self.health().record_sample(timestamp, usable);
+    }
+
+    /// Sends a command to the sensor task, waiting if its command queue is full.
+    pub async fn send_command(&self, command: SensorCommand<C>) {
+        self.state.commands.send(command).await;
+    }
+
+    /// Sends a command to the sensor task without waiting. Usable from interrupts.
+    pub fn try_send_command(
+        &self,
+        command: SensorCommand<C>,
+    ) -> Result<(), TrySendError<SensorCommand<C>>> {
+        self.state.commands.try_send(command)
+    }
+
+    /// Stops the measurements until [`Self::resume`] is called.
+    pub async fn stop(&self) {
+        self.send_command(SensorCommand::Stop).await;
+    }
+
+    /// Restarts continuous measurements after a [`Self::stop`] or a [`Self::single_shot`].
+    pub async fn resume(&self) {
+        self.send_command(SensorCommand::Resume).await;
+    }
+
+    /// Takes a single measurement, publishes it, then stops the sensor.
+    pub async fn single_shot(&self) {
+        self.send_command(SensorCommand::SingleShot).await;
+    }
+
+    /// Applies a new configuration to the running sensor, keeping it in its current mode.
+    pub async fn reconfigure(&self, config: C) {
+        self.send_command(SensorCommand::Reconfigure(config)).await;
+    }
+
+    /// Waits for the next command. Only meant to be called by the sensor task itself.
+    pub(crate) async fn receive_command(&self) -> SensorCommand<C> {
+        self.state.commands.receive().await
+    }
+}
+
+/// A subscription to the measurements of a single sensor
+pub struct Subscription<M: Clone + 'static> {
+    subscriber: Subscriber<
+        'static,
+        CriticalSectionRawMutex,
+        Timestamped<M>,
+        CHANNEL_CAPACITY,
+        MAX_SUBSCRIBERS,
+        0,
+    >,
+    missed: u64,
+}
+
+impl<M: Clone + 'static> Subscription<M> {
+    /// Waits for the next measurement.
+    ///
+    /// Returns [`WaitResult::Lagged`] with the number of dropped measurements if this subscriber
+    /// was too slow to keep up with the sensor. The next call returns the oldest measurement still
+    /// in the buffer.
+    pub async fn next(&mut self) -> WaitResult<Timestamped<M>> {
+        let result = self.subscriber.next_message().await;
+        if let WaitResult::Lagged(count) = result {
+            self.missed += count;
+        }
+        result
+    }
+
+    /// Same as [`Self::next`] but returns `None` instead of waiting if nothing is available.
+    pub fn try_next(&mut self) -> Option<WaitResult<Timestamped<M>>> {
+        let result = self.subscriber.try_next_message();
+        if let Some(WaitResult::Lagged(count)) = result {
+            self.missed += count;
+        }
+        result
+    }
+
+    /// Waits for the next measurement, silently skipping over lag notifications.
+    /// The number of skipped measurements is still counted in [`Self::missed`].
+    pub async fn next_measurement(&mut self) -> Timestamped<M> {
+        loop {
+            if let WaitResult::Message(measurement) = self.next().await {
+                return measurement;
+            }
+        }
+    }
+
+    /// Total number of measurements this subscriber missed because it was lagging
+    pub fn missed(&self) -> u64 {
+        self.missed
+    }
+}
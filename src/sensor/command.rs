@@ -0,0 +1,87 @@
+use crate::sensor::channel::SensorHandle;
+use defmt::{Format, warn};
+use embassy_futures::select::{Either, select};
+use embassy_time::{Duration, Instant, with_timeout};
+use embedded_hal_async::digital::Wait;
+
+/// Command sent to a running sensor task through its [`SensorHandle`]
+#[derive(Debug, Clone, Copy, Format)]
+pub enum SensorCommand<C> {
+    /// Stops taking measurements. The task keeps running and waits for the next command.
+    Stop,
+    /// Restarts continuous measurements
+    Resume,
+    /// Takes one measurement, publishes it, then stops
+    SingleShot,
+    /// Applies a new configuration without changing the current [`RunState`]
+    Reconfigure(C),
+}
+
+/// Measurement mode of a running sensor task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RunState {
+    Continuous,
+    /// The next measurement is the last one before going to [`RunState::Stopped`]
+    SingleShot,
+    Stopped,
+}
+
+impl RunState {
+    /// Returns the state after applying `command`, `Reconfigure` doesn't change it.
+    pub fn after<C>(self, command: &SensorCommand<C>) -> Self {
+        match command {
+            SensorCommand::Stop => RunState::Stopped,
+            SensorCommand::Resume => RunState::Continuous,
+            SensorCommand::SingleShot => RunState::SingleShot,
+            SensorCommand::Reconfigure(_) => self,
+        }
+    }
+
+    /// Whether the sensor hardware has to be ranging in this state
+    pub fn is_measuring(self) -> bool {
+        self != RunState::Stopped
+    }
+}
+
+/// What woke up a sensor task
+pub(crate) enum Event<C> {
+    /// A measurement is ready, it was taken at the given time
+    DataReady(Instant),
+    Command(SensorCommand<C>),
+    /// The data-ready interrupt didn't come in time, the sensor is probably stuck
+    Timeout,
+}
+
+/// Waits for the data-ready interrupt or a command, whichever comes first, giving up after
+/// `timeout` if there is one.
+/// While stopped, the interrupt pin is ignored and only commands are awaited. A pin that can't be
+/// waited on is reported as a timeout, so that the recovery of the sensor kicks in.
+pub(crate) async fn next_event<M: Clone + 'static, C: 'static, W: Wait>(
+    handle: SensorHandle<M, C>,
+    interrupt: &mut W,
+    state: RunState,
+    timeout: Option<Duration>,
+) -> Event<C>
+where
+    W::Error: Format,
+{
+    if !state.is_measuring() {
+        return Event::Command(handle.receive_command().await);
+    }
+    let event = select(interrupt.wait_for_falling_edge(), handle.receive_command());
+    let event = match timeout {
+        Some(timeout) => match with_timeout(timeout, event).await {
+            Ok(event) => event,
+            Err(_) => return Event::Timeout,
+        },
+        None => event.await,
+    };
+    match event {
+        Either::First(Ok(())) => Event::DataReady(Instant::now()),
+        Either::First(Err(e)) => {
+            warn!("Failed to wait for the data-ready interrupt: {}", e);
+            Event::Timeout
+        }
+        Either::Second(command) => Event::Command(command),
+    }
+}
@@ -0,0 +1,41 @@
+use crate::sensor::Sensor;
+use crate::sensor::channel::SensorHandle;
+use crate::sensor::vl53lxx::ToFConfig;
+use core::cell::RefCell;
+use defmt::Format;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+pub use micromouse::reading::{DistanceReading, RangeQuality, SensorPosition};
+
+/// Handle of any distance sensor, whatever the chip
+pub type DistanceHandle = SensorHandle<DistanceReading, ToFConfig>;
+
+/// A Time-of-Flight sensor publishing [`DistanceReading`]s, so that higher layers don't have to
+/// care which chip is mounted where.
+pub trait DistanceSensor<StartError: Format>:
+    Sensor<DistanceReading, StartError, ToFConfig>
+{
+}
+
+impl<S, StartError: Format> DistanceSensor<StartError> for S where
+    S: Sensor<DistanceReading, StartError, ToFConfig>
+{
+}
+
+/// Handles of the distance sensors that are running, indexed by [`SensorPosition`].
+/// A position without a handle is unavailable: nothing is mounted there or it failed to start.
+static DISTANCE_SENSORS: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<[Option<DistanceHandle>; SensorPosition::ALL.len()]>,
+> = Mutex::new(RefCell::new([None; SensorPosition::ALL.len()]));
+
+/// Returns the handle of the sensor at `position`, or `None` if it is unavailable.
+/// A sensor that failed at boot can become available later, when its bring-up is retried.
+pub fn distance_sensor(position: SensorPosition) -> Option<DistanceHandle> {
+    DISTANCE_SENSORS.lock(|sensors| sensors.borrow()[position as usize])
+}
+
+/// Marks the sensor at `position` as available. Called once its task is running.
+pub(crate) fn register_distance_sensor(position: SensorPosition, handle: DistanceHandle) {
+    DISTANCE_SENSORS.lock(|sensors| sensors.borrow_mut()[position as usize] = Some(handle));
+}
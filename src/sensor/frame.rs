@@ -0,0 +1,94 @@
+use crate::sensor::ahrs::{Orientation, orientation};
+use crate::sensor::channel::{SensorHandle, Timestamped};
+use crate::sensor::distance::{DistanceReading, SensorPosition, distance_sensor};
+use crate::sensor::mpu9250::imu;
+use defmt::Format;
+use embassy_executor::{SpawnError, Spawner};
+use embassy_time::{Duration, Instant, Ticker};
+use mpu9250::MargMeasurements;
+
+/// Latest measurement of one sensor in a [`SensorFrame`]
+#[derive(Debug, Clone, Copy, Format)]
+pub struct FrameField<M> {
+    /// `None` if the sensor is unavailable or did not publish anything yet
+    pub latest: Option<Timestamped<M>>,
+    /// Whether `latest` was taken less than the max age of the frames before the frame
+    pub fresh: bool,
+}
+
+impl<M: Clone> FrameField<M> {
+    fn new(latest: Option<Timestamped<M>>, frame_timestamp: Instant, max_age: Duration) -> Self {
+        let fresh = latest.as_ref().is_some_and(|latest| {
+            frame_timestamp.saturating_duration_since(latest.timestamp) <= max_age
+        });
+        Self { latest, fresh }
+    }
+
+    /// The measurement if it is fresh
+    pub fn fresh_measurement(&self) -> Option<&M> {
+        self.latest
+            .as_ref()
+            .filter(|_| self.fresh)
+            .map(|latest| &latest.measurement)
+    }
+}
+
+/// The latest readings of every sensor, gathered at the same time so that they can be used
+/// together. It is published as a [`Timestamped`] carrying the time it was gathered.
+#[derive(Debug, Clone)]
+pub struct SensorFrame {
+    /// Indexed by [`SensorPosition`]
+    pub distances: [FrameField<DistanceReading>; SensorPosition::ALL.len()],
+    pub imu: FrameField<MargMeasurements<[f32; 3]>>,
+    pub orientation: FrameField<Orientation>,
+}
+
+impl SensorFrame {
+    pub fn distance(&self, position: SensorPosition) -> &FrameField<DistanceReading> {
+        &self.distances[position as usize]
+    }
+
+    /// Gathers the latest measurements of the sensors that are running
+    fn gather(timestamp: Instant, max_age: Duration) -> Self {
+        let distance = |position| {
+            let latest = distance_sensor(position).and_then(|handle| handle.latest());
+            FrameField::new(latest, timestamp, max_age)
+        };
+        Self {
+            distances: SensorPosition::ALL.map(distance),
+            imu: FrameField::new(imu().and_then(|handle| handle.latest()), timestamp, max_age),
+            orientation: FrameField::new(
+                orientation().and_then(|handle| handle.latest()),
+                timestamp,
+                max_age,
+            ),
+        }
+    }
+}
+
+/// Handle of the frames published by [`frame_task`]
+pub type FrameHandle = SensorHandle<SensorFrame>;
+
+/// Starts publishing a [`SensorFrame`] every `period`. A measurement is flagged fresh in a frame
+/// if it was taken less than `max_age` before it.
+///
+/// Sensors that come up later, or that stop, are taken into account in the next frame.
+pub fn start_frames(
+    spawner: &mut Spawner,
+    period: Duration,
+    max_age: Duration,
+) -> Result<FrameHandle, SpawnError> {
+    let handle = FrameHandle::new();
+    spawner.spawn(frame_task(handle, period, max_age))?;
+    Ok(handle)
+}
+
+#[embassy_executor::task]
+async fn frame_task(handle: FrameHandle, period: Duration, max_age: Duration) -> ! {
+    let mut ticker = Ticker::every(period);
+    loop {
+        ticker.next().await;
+        let timestamp = Instant::now();
+        handle.publish(SensorFrame::gather(timestamp, max_age), timestamp);
+    }
+}
@@ -0,0 +1,133 @@
+use crate::sensor::distance::{DistanceReading, SensorPosition};
+use crate::sensor::vl53lxx::scan::WallEstimate;
+use core::f32::consts::{FRAC_PI_2, FRAC_PI_4};
+use defmt::Format;
+use micromath::F32Ext;
+
+/// Revision of the board the firmware is built for, selects the geometry of the sensors
+pub const BOARD_REVISION: BoardRevision = BoardRevision::Rev1;
+
+/// Where a distance sensor is mounted, in the robot frame: the origin is the centre of the robot,
+/// between the wheels, x points forward and y to the left. Angles are counterclockwise from x.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct SensorMount {
+    pub position: SensorPosition,
+    pub x_mm: f32,
+    pub y_mm: f32,
+    /// Direction the sensor looks at
+    pub heading_rad: f32,
+    /// Whether the higher SPAD columns of a VL53L1X look to the left of its axis, which depends on
+    /// which way up it is soldered. See [`crate::sensor::vl53lxx::Roi`].
+    pub columns_to_left: bool,
+}
+
+/// A flat wall seen by a distance sensor, in the robot frame
+#[derive(Debug, Clone, Copy, Format)]
+pub struct Wall {
+    /// Distance between the centre of the robot and the wall, perpendicularly to the wall
+    pub distance_mm: f32,
+    /// Angle between the perpendicular to the wall and the heading of the sensor, counterclockwise.
+    /// 0 when the robot is square with the wall.
+    pub angle_rad: f32,
+}
+
+impl SensorMount {
+    /// Point of the wall hit by a measurement of `distance_mm`, as `(x, y)` in the robot frame
+    pub fn hit_point(&self, distance_mm: f32) -> (f32, f32) {
+        (
+            self.x_mm + distance_mm * self.heading_rad.cos(),
+            self.y_mm + distance_mm * self.heading_rad.sin(),
+        )
+    }
+
+    /// Wall seen by a single reading, assumed square with the sensor. `None` if the reading is not
+    /// usable.
+    pub fn wall_from_reading(&self, reading: &DistanceReading) -> Option<Wall> {
+        reading
+            .is_usable()
+            .then(|| self.wall(reading.mm as f32, 0.0))
+    }
+
+    /// Wall estimated by a sensor scanning several zones
+    pub fn wall_from_estimate(&self, estimate: &WallEstimate) -> Wall {
+        // A wall getting further towards the higher columns faces the lower ones
+        let angle_rad = if self.columns_to_left {
+            -estimate.angle_rad
+        } else {
+            estimate.angle_rad
+        };
+        self.wall(estimate.distance_mm, angle_rad)
+    }
+
+    /// Moves a wall at `distance_mm` from the sensor, whose perpendicular is at `angle_rad` from
+    /// the sensor heading, to the robot frame
+    fn wall(&self, distance_mm: f32, angle_rad: f32) -> Wall {
+        let normal_rad = self.heading_rad + angle_rad;
+        Wall {
+            distance_mm: distance_mm + self.x_mm * normal_rad.cos() + self.y_mm * normal_rad.sin(),
+            angle_rad,
+        }
+    }
+}
+
+/// Hardware revisions of the robot board, they differ by the placement of the sensors
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum BoardRevision {
+    Rev1,
+}
+
+impl BoardRevision {
+    /// Every sensor footprint of the board, mounted or not
+    pub fn mounts(&self) -> &'static [SensorMount] {
+        match self {
+            BoardRevision::Rev1 => &REV1_MOUNTS,
+        }
+    }
+}
+
+const REV1_MOUNTS: [SensorMount; 5] = [
+    SensorMount {
+        position: SensorPosition::Left,
+        x_mm: 30.0,
+        y_mm: 25.0,
+        heading_rad: FRAC_PI_2,
+        columns_to_left: false,
+    },
+    SensorMount {
+        position: SensorPosition::FrontLeft,
+        x_mm: 40.0,
+        y_mm: 20.0,
+        heading_rad: FRAC_PI_4,
+        columns_to_left: false,
+    },
+    SensorMount {
+        position: SensorPosition::Front,
+        x_mm: 45.0,
+        y_mm: 0.0,
+        heading_rad: 0.0,
+        columns_to_left: true,
+    },
+    SensorMount {
+        position: SensorPosition::FrontRight,
+        x_mm: 40.0,
+        y_mm: -20.0,
+        heading_rad: -FRAC_PI_4,
+        columns_to_left: false,
+    },
+    SensorMount {
+        position: SensorPosition::Right,
+        x_mm: 30.0,
+        y_mm: -25.0,
+        heading_rad: -FRAC_PI_2,
+        columns_to_left: false,
+    },
+];
+
+/// Mounting of the sensor at `position` on [`BOARD_REVISION`], `None` if the board has no
+/// footprint there
+pub fn mount(position: SensorPosition) -> Option<&'static SensorMount> {
+    BOARD_REVISION
+        .mounts()
+        .iter()
+        .find(|mount| mount.position == position)
+}
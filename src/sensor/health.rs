@@ -0,0 +1,230 @@
+use crate::sensor::distance::SensorPosition;
+use core::cell::RefCell;
+use defmt::{Format, info, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+
+/// Time over which the sample rate is averaged
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// What went wrong in a sensor task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ErrorKind {
+    /// Reading a measurement or clearing its interrupt failed
+    Read,
+    /// A command could not be applied
+    Command,
+    /// A restart or a hard reset failed
+    Recovery,
+}
+
+/// Something the health monitor counts
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum HealthEvent {
+    Error(ErrorKind),
+    /// The data-ready interrupt didn't come in time
+    InterruptTimeout,
+    /// The measurements were stopped and started again
+    Restart,
+    /// The sensor was power cycled and initialized again
+    HardReset,
+    /// The buffer of the sensor filled up before it was read, samples were lost
+    Overflow,
+}
+
+#[derive(Debug, Clone, Copy, Default, Format)]
+pub struct ErrorCounts {
+    pub read: u32,
+    pub command: u32,
+    pub recovery: u32,
+}
+
+impl ErrorCounts {
+    pub fn total(&self) -> u32 {
+        self.read + self.command + self.recovery
+    }
+}
+
+/// Statistics of a sensor since boot
+#[derive(Debug, Clone, Copy, Default, Format)]
+pub struct HealthSnapshot {
+    /// Number of measurements published
+    pub samples: u32,
+    /// Measurements published per second, averaged over the last second
+    pub rate_hz: f32,
+    pub errors: ErrorCounts,
+    pub interrupt_timeouts: u32,
+    pub restarts: u32,
+    pub hard_resets: u32,
+    pub overflows: u32,
+    /// When the last usable measurement was taken, `None` if there was none yet. Readings
+    /// without a target or invalid ones are counted in `samples` but don't update it.
+    pub last_good: Option<Instant>,
+}
+
+/// Health statistics of a sensor, shared between its task and the rest of the firmware
+pub struct HealthState {
+    inner: Mutex<CriticalSectionRawMutex, RefCell<HealthCounters>>,
+}
+
+struct HealthCounters {
+    snapshot: HealthSnapshot,
+    /// When the last measurement was taken, usable or not
+    last_sample: Option<Instant>,
+    window_start: Option<Instant>,
+    window_samples: u32,
+}
+
+impl HealthState {
+    pub const fn new() -> Self {
+        Self {
+            inner: Mutex::new(RefCell::new(HealthCounters {
+                snapshot: HealthSnapshot {
+                    samples: 0,
+                    rate_hz: 0.0,
+                    errors: ErrorCounts {
+                        read: 0,
+                        command: 0,
+                        recovery: 0,
+                    },
+                    interrupt_timeouts: 0,
+                    restarts: 0,
+                    hard_resets: 0,
+                    overflows: 0,
+                    last_good: None,
+                },
+                last_sample: None,
+                window_start: None,
+                window_samples: 0,
+            })),
+        }
+    }
+}
+
+/// Cheap, copyable handle to the health statistics of a sensor, whatever its measurement type
+#[derive(Clone, Copy)]
+pub struct HealthHandle {
+    state: &'static HealthState,
+}
+
+impl HealthHandle {
+    pub(crate) fn new(state: &'static HealthState) -> Self {
+        Self { state }
+    }
+
+    /// Returns a copy of the statistics. Safe to call from any task or interrupt.
+    pub fn snapshot(&self) -> HealthSnapshot {
+        let (mut snapshot, last_sample) = self.state.inner.lock(|counters| {
+            let counters = counters.borrow();
+            (counters.snapshot, counters.last_sample)
+        });
+        // The rate is only updated when a sample comes, don't report it for a silent sensor
+        if last_sample.is_none_or(|last_sample| last_sample.elapsed() > RATE_WINDOW * 2) {
+            snapshot.rate_hz = 0.0;
+        }
+        snapshot
+    }
+
+    /// Counts a measurement taken at `timestamp`, `usable` telling whether it is a good one
+    pub(crate) fn record_sample(&self, timestamp: Instant, usable: bool) {
+        self.state.inner.lock(|counters| {
+            let mut counters = counters.borrow_mut();
+            counters.snapshot.samples = counters.snapshot.samples.wrapping_add(1);
+            counters.last_sample = Some(timestamp);
+            if usable {
+                counters.snapshot.last_good = Some(timestamp);
+            }
+            counters.window_samples += 1;
+            match counters.window_start {
+                // The samples of a batch can be older than the previous one, don't go backwards
+                Some(start) if timestamp.saturating_duration_since(start) >= RATE_WINDOW => {
+                    let elapsed = timestamp.saturating_duration_since(start);
+                    let elapsed_s = elapsed.as_micros() as f32 / 1_000_000.0;
+                    counters.snapshot.rate_hz = counters.window_samples as f32 / elapsed_s;
+                    counters.window_start = Some(timestamp);
+                    counters.window_samples = 0;
+                }
+                Some(_) => {}
+                None => {
+                    counters.window_start = Some(timestamp);
+                    counters.window_samples = 0;
+                }
+            }
+        });
+    }
+
+    pub(crate) fn record(&self, event: HealthEvent) {
+        self.state.inner.lock(|counters| {
+            let snapshot = &mut counters.borrow_mut().snapshot;
+            let counter = match event {
+                HealthEvent::Error(ErrorKind::Read) => &mut snapshot.errors.read,
+                HealthEvent::Error(ErrorKind::Command) => &mut snapshot.errors.command,
+                HealthEvent::Error(ErrorKind::Recovery) => &mut snapshot.errors.recovery,
+                HealthEvent::InterruptTimeout => &mut snapshot.interrupt_timeouts,
+                HealthEvent::Restart => &mut snapshot.restarts,
+                HealthEvent::HardReset => &mut snapshot.hard_resets,
+                HealthEvent::Overflow => &mut snapshot.overflows,
+            };
+            *counter = counter.wrapping_add(1);
+        });
+    }
+}
+
+/// Which sensor a health summary is about
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum SensorId {
+    Distance(SensorPosition),
+    Imu,
+}
+
+/// Every distance sensor and the IMU
+const MAX_MONITORED: usize = SensorPosition::ALL.len() + 1;
+
+/// Sensors included in the periodic summary
+static MONITORED: Mutex<
+    CriticalSectionRawMutex,
+    RefCell<Vec<(SensorId, HealthHandle), MAX_MONITORED>>,
+> = Mutex::new(RefCell::new(Vec::new()));
+
+/// Adds a running sensor to the periodic summary of [`health_report_task`]
+pub fn monitor(id: SensorId, health: HealthHandle) {
+    let added = MONITORED.lock(|monitored| monitored.borrow_mut().push((id, health)).is_ok());
+    if !added {
+        warn!(
+            "{} not included in the health summary, too many sensors",
+            id
+        );
+    }
+}
+
+/// Logs the health of every monitored sensor every `period`
+#[embassy_executor::task]
+pub async fn health_report_task(period: Duration) -> ! {
+    loop {
+        Timer::after(period).await;
+        let monitored = MONITORED.lock(|monitored| monitored.borrow().clone());
+        for (id, health) in monitored {
+            let health = health.snapshot();
+            let last_good_ms = health
+                .last_good
+                .map(|last_good| last_good.elapsed().as_millis());
+            info!(
+                "{}: {} Hz, {} samples, last good {} ms ago",
+                id, health.rate_hz, health.samples, last_good_ms
+            );
+            if health.errors.total() > 0 || health.interrupt_timeouts > 0 || health.overflows > 0 {
+                warn!(
+                    "{}: errors {}, {} interrupt timeouts, {} restarts, {} hard resets, {} overflows",
+                    id,
+                    health.errors,
+                    health.interrupt_timeouts,
+                    health.restarts,
+                    health.hard_resets,
+                    health.overflows
+                );
+            }
+        }
+    }
+}
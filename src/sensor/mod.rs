@@ -1,17 +1,91 @@
+use crate::sensor::channel::SensorHandle;
+use alloc::boxed::Box;
+use core::convert::Infallible;
 use defmt::Format;
-use embassy_executor::Spawner;
+use embassy_executor::{SpawnToken, Spawner};
 
+pub mod ahrs;
+pub mod channel;
+pub mod command;
+pub mod distance;
+pub mod frame;
+pub mod geometry;
+pub mod health;
 pub mod vl53lxx;
+pub mod walls;
 pub mod mpu9250;
 
-pub trait Sensor<M, StartError: Format>: Sized {
+/// A sensor publishing measurements of type `M` from its own task. `C` is the configuration that
+/// can be changed while the task is running, see [`command::SensorCommand::Reconfigure`].
+pub trait Sensor<M: Clone + 'static, StartError: Format, C: 'static = ()>: Sized + 'static {
     /// Starts continuous measurement mode, where the sensor will automatically take measurements at
-    /// a fixed interval and call the provided callback with the new measurement data.
-    async fn start_continuous_measurement(
+    /// a fixed interval and publish them to every subscriber of its [`SensorHandle`].
+    ///
+    /// `task` is the embassy task declared with [`sensor_task!`] for this sensor type. On error,
+    /// the task was not spawned and `self` is not kept, see [`start_on_heap`].
+    async fn start_continuous_measurement<S>(
         &'static mut self,
         spawner: &mut Spawner,
-        callable: &'static dyn Fn(&M),
-    ) -> Result<(), StartError>;
+        task: impl FnOnce(&'static mut Self) -> SpawnToken<S>,
+    ) -> Result<SensorHandle<M, C>, StartError>;
 
-    fn get_latest_measurement(&self) -> &M;
+    /// Returns the handle to subscribe to this sensor's measurements and to control it.
+    /// Subscribing before starting the measurements guarantees that the first one is not missed.
+    fn handle(&self) -> SensorHandle<M, C>;
+
+    /// Body of the sensor task: waits for new data and publishes it, and executes the commands
+    /// received through the handle, forever.
+    async fn run(&mut self) -> Infallible;
+}
+
+/// Moves `sensor` to the heap to get the `'static` lifetime its task requires, and starts it.
+///
+/// The sensor is only leaked once its task runs. If it could not be started, it is given back with
+/// the error so that starting it can be retried.
+pub async fn start_on_heap<M, E, C, T, S>(
+    spawner: &mut Spawner,
+    sensor: T,
+    task: impl FnOnce(&'static mut T) -> SpawnToken<S>,
+) -> Result<SensorHandle<M, C>, (E, T)>
+where
+    M: Clone + 'static,
+    E: Format,
+    C: 'static,
+    T: Sensor<M, E, C>,
+{
+    let sensor = Box::into_raw(Box::new(sensor));
+    // SAFETY: the pointer comes from a box that is never freed while the reference lives
+    let started = unsafe { &mut *sensor }
+        .start_continuous_measurement(spawner, task)
+        .await;
+    started.map_err(|e| {
+        // SAFETY: the start failed, so the sensor didn't keep its reference and nothing else
+        // points to it
+        (e, *unsafe { Box::from_raw(sensor) })
+    })
 }
+
+/// Declares the embassy task running a [`Sensor`].
+///
+/// Embassy tasks can't be generic, so one task has to be declared for each concrete sensor type,
+/// i.e. for each bus and pin types a driver is used with. The optional pool size is the number of
+/// sensors of that type that can run at the same time.
+///
+/// ```ignore
+/// sensor_task!(vl53l0x_task, VL53L0XSensor<I2c1Device, I2c1Xshut, I2c1Interrupt>, 2);
+///
+/// sensor.start_continuous_measurement(spawner, vl53l0x_task).await?;
+/// ```
+macro_rules! sensor_task {
+    ($name:ident, $sensor:ty) => {
+        $crate::sensor::sensor_task!($name, $sensor, 1);
+    };
+    ($name:ident, $sensor:ty, $pool_size:expr) => {
+        #[embassy_executor::task(pool_size = $pool_size)]
+        async fn $name(sensor: &'static mut $sensor) -> ! {
+            match $crate::sensor::Sensor::run(sensor).await {}
+        }
+    };
+}
+
+pub(crate) use sensor_task;
@@ -0,0 +1,175 @@
+use crate::flash::SharedFlash;
+use crate::sensor::mpu9250::{ImuConfig, ImuHandle, imu};
+use defmt::{error, info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, with_timeout};
+use embedded_storage::nor_flash::NorFlash;
+use micromouse::mag_calibration::MagCalibrator;
+pub use micromouse::mag_calibration::{MagCalibration, MagCalibrationConfig, MagCalibrationError};
+
+const READING_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Collects the raw magnetometer samples published by the IMU for `duration`
+async fn collect(
+    handle: ImuHandle,
+    duration: Duration,
+) -> Result<MagCalibrator, MagCalibrationError> {
+    let mut subscription = handle
+        .subscribe()
+        .map_err(|_| MagCalibrationError::NoSubscriber)?;
+    let mut calibrator = MagCalibrator::new();
+    let end = Instant::now() + duration;
+    while Instant::now() < end {
+        let sample = with_timeout(READING_TIMEOUT, subscription.next_measurement())
+            .await
+            .map_err(|_| MagCalibrationError::Timeout)?;
+        calibrator.add(sample.measurement.mag);
+    }
+    Ok(calibrator)
+}
+
+/// First bytes of the calibration storage, the rest is ignored if they don't match. Changed with the
+/// unit of the field: "Mag1" calibrations were fitted without the factory sensitivity adjustment.
+const MAGIC: [u8; 4] = *b"Mag2";
+/// Size of the stored data: magic, enabled flag, padding, offset, scale
+const STORAGE_SIZE: usize = 32;
+const ENABLED: u8 = 0x01;
+
+/// Calibration of the magnetometer, persisted in a flash sector reserved for it
+pub struct MagCalibrationStore<F: NorFlash> {
+    flash: F,
+    /// Offset of the reserved sector from the start of the flash
+    offset: u32,
+    calibration: Option<MagCalibration>,
+}
+
+impl<F: NorFlash> MagCalibrationStore<F> {
+    /// Reads the calibration saved at `offset`. Starts empty if nothing valid is stored there.
+    pub fn load(mut flash: F, offset: u32) -> Self {
+        let mut data = [0u8; STORAGE_SIZE];
+        let calibration = if flash.read(offset, &mut data).is_err() || data[..MAGIC.len()] != MAGIC
+        {
+            info!("No magnetometer calibration stored");
+            None
+        } else {
+            let value = |index: usize| {
+                let start = 8 + 4 * index;
+                f32::from_le_bytes([
+                    data[start],
+                    data[start + 1],
+                    data[start + 2],
+                    data[start + 3],
+                ])
+            };
+            Some(MagCalibration {
+                offset: core::array::from_fn(value),
+                scale: core::array::from_fn(|axis| value(3 + axis)),
+                enabled: data[4] == ENABLED,
+            })
+        };
+        Self {
+            flash,
+            offset,
+            calibration,
+        }
+    }
+
+    /// The stored calibration, `None` if the magnetometer was never calibrated
+    pub fn get(&self) -> Option<MagCalibration> {
+        self.calibration
+    }
+
+    pub fn set(&mut self, calibration: MagCalibration) {
+        self.calibration = Some(calibration);
+    }
+
+    /// Erases the reserved sector and writes the calibration to it
+    pub fn save(&mut self) -> Result<(), F::Error> {
+        let Some(calibration) = self.calibration else {
+            return Ok(());
+        };
+        let mut data = [0xFF; STORAGE_SIZE];
+        data[..MAGIC.len()].copy_from_slice(&MAGIC);
+        data[4] = if calibration.enabled { ENABLED } else { 0 };
+        data[5..8].fill(0);
+        for (index, value) in calibration
+            .offset
+            .iter()
+            .chain(calibration.scale.iter())
+            .enumerate()
+        {
+            let start = 8 + 4 * index;
+            data[start..start + 4].copy_from_slice(&value.to_le_bytes());
+        }
+        self.flash
+            .erase(self.offset, self.offset + F::ERASE_SIZE as u32)?;
+        self.flash.write(self.offset, &data)
+    }
+}
+
+/// Set to start a calibration of the magnetometer
+static MAG_CALIBRATION_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Starts a calibration of the magnetometer. The robot must then spin at least one full turn,
+/// flat, during [`MagCalibrationConfig::duration`]. Usable from interrupts.
+pub fn request_mag_calibration() {
+    MAG_CALIBRATION_REQUEST.signal(());
+}
+
+/// Calibrates the magnetometer on request, applies the result to the IMU and saves it in flash so
+/// that it is applied at the next boot.
+///
+/// A calibration of poor quality is still saved, with the magnetometer disabled, so that the
+/// distorted field is not used until the next calibration.
+#[embassy_executor::task]
+pub async fn mag_calibration_task(
+    mut store: MagCalibrationStore<SharedFlash>,
+    config: MagCalibrationConfig,
+) -> ! {
+    loop {
+        MAG_CALIBRATION_REQUEST.wait().await;
+        let Some(handle) = imu() else {
+            warn!("IMU unavailable, magnetometer not calibrated");
+            continue;
+        };
+        info!("Magnetometer calibration started, spin the robot");
+        let old = store.get().unwrap_or_default();
+        handle
+            .reconfigure(ImuConfig::MagCalibration(MagCalibration::NONE))
+            .await;
+        let result = collect(handle, config.duration)
+            .await
+            .and_then(|calibrator| calibrator.fit(&config));
+        let calibration = match result {
+            Ok((calibration, quality)) if calibration.enabled => {
+                info!(
+                    "Magnetometer offset: {}, scale: {}, {}",
+                    calibration.offset, calibration.scale, quality
+                );
+                calibration
+            }
+            Ok((calibration, quality)) => {
+                warn!(
+                    "Poor magnetometer calibration, {}, magnetometer disabled",
+                    quality
+                );
+                calibration
+            }
+            Err(e) => {
+                error!("Magnetometer calibration failed: {}", e);
+                handle.reconfigure(ImuConfig::MagCalibration(old)).await;
+                continue;
+            }
+        };
+        handle
+            .reconfigure(ImuConfig::MagCalibration(calibration))
+            .await;
+        store.set(calibration);
+        match store.save() {
+            Ok(()) => info!("Magnetometer calibration saved"),
+            Err(e) => error!("Failed to save the magnetometer calibration: {}", e),
+        }
+        MAG_CALIBRATION_REQUEST.reset();
+    }
+}
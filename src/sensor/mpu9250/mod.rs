@@ -0,0 +1,218 @@
+use crate::sensor::Sensor;
+use crate::sensor::channel::SensorHandle;
+use crate::sensor::command::{Event, RunState, SensorCommand, next_event};
+use crate::sensor::health::{ErrorKind, HealthEvent};
+use core::cell::RefCell;
+use core::convert::Infallible;
+use defmt::Format;
+use embassy_executor::{SpawnError, SpawnToken, Spawner};
+use embassy_futures::yield_now;
+use embassy_stm32::exti::ExtiInput;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{Delay, Instant};
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiBus;
+use embedded_hal_async::spi::SpiBus as AsyncSpiBus;
+use mag_calibration::MagCalibration;
+use micromouse::gyro_calibration::{GyroBias, GyroCalibration, GyroCalibrationConfig};
+use micromouse::mpu9250_fifo::{Fifo, RegisterError, SamplingConfig};
+use mpu9250::{Error, MargMeasurements, Mpu9250, SpiError};
+
+pub mod mag_calibration;
+
+/// Settings of the IMU applied when its task starts
+#[derive(Debug, Clone, Copy, Default, Format)]
+pub struct Mpu9250Config {
+    pub sampling: SamplingConfig,
+    pub gyro_calibration: GyroCalibrationConfig,
+    pub mag_calibration: MagCalibration,
+}
+
+/// Configuration of the IMU that can be changed while its task is running
+#[derive(Debug, Clone, Copy, Format)]
+pub enum ImuConfig {
+    Sampling(SamplingConfig),
+    MagCalibration(MagCalibration),
+}
+
+/// Why the MPU9250 could not be initialized
+#[derive(Debug, Format)]
+pub enum ImuError<S, C> {
+    Driver(Error<SpiError<S, C>>),
+    Register(RegisterError<S, C>),
+}
+
+/// Handle of the IMU, whatever the bus it is on
+pub type ImuHandle = SensorHandle<MargMeasurements<[f32; 3]>, ImuConfig>;
+
+/// Handle of the IMU once its task is running
+static IMU: Mutex<CriticalSectionRawMutex, RefCell<Option<ImuHandle>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Returns the handle of the IMU, or `None` if it is not running
+pub fn imu() -> Option<ImuHandle> {
+    IMU.lock(|imu| *imu.borrow())
+}
+
+/// Marks the IMU as available. Called once its task is running.
+pub(crate) fn register_imu(handle: ImuHandle) {
+    IMU.lock(|imu| *imu.borrow_mut() = Some(handle));
+}
+
+pub struct Mpu9250Sensor<SPI: SpiBus + AsyncSpiBus, NCS: OutputPin> {
+    fifo: Fifo<SPI, NCS>,
+    /// Raised when the FIFO overflows
+    gpio_interrupt: ExtiInput<'static>,
+    handle: ImuHandle,
+    sampling: SamplingConfig,
+    /// `Some` until the gyroscope bias is known, the samples are not published meanwhile
+    gyro_calibration: Option<GyroCalibration>,
+    gyro_bias: GyroBias,
+    mag_calibration: MagCalibration,
+}
+
+impl<SPI: SpiBus + AsyncSpiBus + 'static, NCS: OutputPin + 'static>
+    Sensor<MargMeasurements<[f32; 3]>, SpawnError, ImuConfig> for Mpu9250Sensor<SPI, NCS>
+where
+    SPI::Error: Format,
+    NCS::Error: Format,
+{
+    async fn start_continuous_measurement<S>(
+        &'static mut self,
+        spawner: &mut Spawner,
+        task: impl FnOnce(&'static mut Self) -> SpawnToken<S>,
+    ) -> Result<ImuHandle, SpawnError> {
+        let handle = self.handle;
+        spawner.spawn(task(self))?;
+        Ok(handle)
+    }
+
+    fn handle(&self) -> ImuHandle {
+        self.handle
+    }
+
+    /// The MPU9250 keeps sampling when stopped, the task only stops reading its FIFO. The FIFO
+    /// is emptied when the measurements resume.
+    async fn run(&mut self) -> Infallible {
+        let mut state = RunState::Continuous;
+        loop {
+            let read_period = Some(self.sampling.read_period);
+            match next_event(self.handle, &mut self.gpio_interrupt, state, read_period).await {
+                // The interrupt only signals an overflow, the FIFO is read either way
+                Event::DataReady(_) | Event::Timeout => {}
+                Event::Command(command) => {
+                    let new_state = state.after(&command);
+                    if let Err(e) = self.apply_command(command, state, new_state) {
+                        defmt::error!("Failed to apply command: {}", e);
+                        self.handle.record(HealthEvent::Error(ErrorKind::Command));
+                    }
+                    state = new_state;
+                    continue;
+                }
+            }
+            let batch = match self.fifo.read_batch().await {
+                Ok(batch) => batch,
+                Err(e) => {
+                    defmt::error!("Failed to read the FIFO: {}", e);
+                    self.handle.record(HealthEvent::Error(ErrorKind::Read));
+                    continue;
+                }
+            };
+            if batch.overflow {
+                defmt::warn!("IMU FIFO overflow, samples lost");
+                self.handle.record(HealthEvent::Overflow);
+            }
+            // A single shot only publishes the newest sample
+            let first = if state == RunState::SingleShot {
+                batch.samples.saturating_sub(1)
+            } else {
+                0
+            };
+            for index in first..batch.samples {
+                let (data, timestamp) = self.fifo.sample(&batch, index);
+                if self.calibrate_gyro(&data, timestamp) {
+                    continue;
+                }
+                let data = self.mag_calibration.correct(self.gyro_bias.correct(data));
+                self.handle.publish(data, timestamp);
+                // Let the subscribers take it, the channel only buffers a few samples
+                yield_now().await;
+                if state == RunState::SingleShot {
+                    state = RunState::Stopped;
+                }
+            }
+        }
+    }
+}
+
+impl<SPI: SpiBus + AsyncSpiBus, NCS: OutputPin> Mpu9250Sensor<SPI, NCS> {
+    pub(crate) fn init_new(
+        com: SPI,
+        ncs: NCS,
+        gpio_interrupt: ExtiInput<'static>,
+        config: Mpu9250Config,
+    ) -> Result<Self, ImuError<SPI::Error, NCS::Error>> {
+        defmt::info!("Initializing MPU9250 via SPI...");
+        let device = Mpu9250::marg_default(com, ncs, &mut Delay).map_err(ImuError::Driver)?;
+        let mag_sensitivity = device.mag_sensitivity_adjustments();
+        // The driver reads one sample at a time, the FIFO is read directly instead
+        let (com, ncs) = device.release();
+        let fifo =
+            Fifo::new(com, ncs, &config.sampling, mag_sensitivity).map_err(ImuError::Register)?;
+        defmt::info!("MPU9250 initialized successfully");
+        Ok(Self {
+            fifo,
+            gpio_interrupt,
+            handle: SensorHandle::new(),
+            sampling: config.sampling,
+            gyro_calibration: Some(GyroCalibration::new(config.gyro_calibration)),
+            gyro_bias: GyroBias::NONE,
+            mag_calibration: config.mag_calibration,
+        })
+    }
+
+    fn apply_command(
+        &mut self,
+        command: SensorCommand<ImuConfig>,
+        state: RunState,
+        new_state: RunState,
+    ) -> Result<(), RegisterError<SPI::Error, NCS::Error>> {
+        match command {
+            SensorCommand::Reconfigure(ImuConfig::Sampling(sampling)) => {
+                self.fifo.configure(&sampling)?;
+                self.sampling = sampling;
+            }
+            SensorCommand::Reconfigure(ImuConfig::MagCalibration(calibration)) => {
+                self.mag_calibration = calibration;
+            }
+            // Drop the samples buffered while stopped
+            _ if new_state.is_measuring() && !state.is_measuring() => self.fifo.reset()?,
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Feeds a sample to the gyroscope calibration, returns whether it is still running
+    fn calibrate_gyro(&mut self, data: &MargMeasurements<[f32; 3]>, timestamp: Instant) -> bool {
+        let Some(calibration) = &mut self.gyro_calibration else {
+            return false;
+        };
+        match calibration.update(data, timestamp) {
+            None => return true,
+            Some(Ok(bias)) => {
+                defmt::info!(
+                    "Gyroscope bias: {} rad/s at {}°C",
+                    bias.offset,
+                    bias.temperature
+                );
+                self.gyro_bias = bias;
+            }
+            Some(Err(e)) => {
+                defmt::warn!("Gyroscope calibration failed: {}, running uncorrected", e);
+            }
+        }
+        self.gyro_calibration = None;
+        false
+    }
+}
@@ -0,0 +1,189 @@
+use crate::i2c_bus::SharedBus;
+use crate::sensor::Sensor;
+use crate::sensor::distance::{DistanceHandle, SensorPosition};
+use crate::sensor::vl53lxx::vl53l0x::VL53L0XSensor;
+use crate::sensor::vl53lxx::vl53l1x::VL53L1XSensor;
+use crate::sensor::vl53lxx::{ChipKind, Config};
+use defmt::{Format, error, info, warn};
+use embassy_time::{Delay, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::digital::Wait;
+use heapless::Vec;
+use micromouse::vl53lxx_bus::{PowerError, RESET_TIME, power_up};
+
+/// Maximum number of ToF sensors on the same bus
+pub const MAX_SENSORS: usize = 6;
+
+/// A ToF sensor in the bring-up table, with its XSHUT pin `P` and data-ready interrupt pin `W`
+pub struct SensorSlot<P, W> {
+    pub position: SensorPosition,
+    pub chip: ChipKind,
+    /// `config.address` must be unique on the bus
+    pub config: Config<P, W>,
+}
+
+/// Why a sensor could not be brought up, `E` being the error of the bus and `P` the one of the
+/// XSHUT pin
+#[derive(Debug, Format)]
+pub enum BringUpError<E, P> {
+    /// The XSHUT pin could not be driven
+    Xshut(P),
+    /// The sensor didn't acknowledge its new address, it is probably not connected
+    Address(E),
+    VL53L0X(vl53l0x::Error<E>),
+    VL53L1X(vl53l1::Error<E>),
+}
+
+impl<E, P> From<PowerError<E, P>> for BringUpError<E, P> {
+    fn from(error: PowerError<E, P>) -> Self {
+        match error {
+            PowerError::Xshut(e) => BringUpError::Xshut(e),
+            PowerError::Address(e) => BringUpError::Address(e),
+        }
+    }
+}
+
+/// A ToF sensor that was successfully brought up, whatever its chip
+pub enum ToFSensor<I: SharedBus, P: OutputPin, W: Wait> {
+    VL53L0X(VL53L0XSensor<I, P, W>),
+    VL53L1X(VL53L1XSensor<I, P, W>),
+}
+
+impl<I: SharedBus + 'static, P: OutputPin + 'static, W: Wait + 'static> ToFSensor<I, P, W>
+where
+    I::Error: Format,
+    P::Error: Format,
+    W::Error: Format,
+{
+    pub fn handle(&self) -> DistanceHandle {
+        match self {
+            ToFSensor::VL53L0X(sensor) => sensor.handle(),
+            ToFSensor::VL53L1X(sensor) => sensor.handle(),
+        }
+    }
+}
+
+/// A sensor that did not come up. It is held in reset so that it doesn't answer at
+/// the default address, and its slot is given back so that the bring-up can be retried.
+pub struct FailedSensor<E, P: OutputPin, W> {
+    pub slot: SensorSlot<P, W>,
+    pub error: BringUpError<E, P::Error>,
+}
+
+/// Outcome of the bring-up of `N` sensors
+pub struct BringUpReport<I: SharedBus, P: OutputPin, W: Wait, const N: usize> {
+    pub sensors: Vec<(SensorPosition, ToFSensor<I, P, W>), N>,
+    pub failed: Vec<FailedSensor<I::Error, P, W>, N>,
+}
+
+/// Brings up every sensor of the table on a shared bus.
+///
+/// All the sensors are held in reset, then woken up one at a time and moved to their address, so
+/// that only one sensor ever answers at the default address. Every sensor gets its own clone of
+/// `i2c`.
+pub async fn bring_up<I: SharedBus, P: OutputPin, W: Wait, const N: usize>(
+    mut slots: [SensorSlot<P, W>; N],
+    i2c: I,
+) -> BringUpReport<I, P, W, N>
+where
+    I::Error: Format,
+    P::Error: Format,
+{
+    for slot in slots.iter_mut() {
+        hold_in_reset(slot);
+    }
+    Timer::after(RESET_TIME).await;
+
+    let mut report = BringUpReport {
+        sensors: Vec::new(),
+        failed: Vec::new(),
+    };
+    // There are no more sensors than slots, the report can't be full
+    for slot in slots {
+        let position = slot.position;
+        match wake_up(slot, i2c.clone()).await {
+            Ok(sensor) => {
+                info!("{} distance sensor up", position);
+                let _ = report.sensors.push((position, sensor));
+            }
+            Err(failed) => {
+                error!("{} distance sensor failed: {}", position, failed.error);
+                let _ = report.failed.push(failed);
+            }
+        }
+    }
+    report
+}
+
+/// Releases the sensor of the slot from reset, moves it to its address and initializes it.
+/// On failure, the sensor is put back in reset.
+pub async fn wake_up<I: SharedBus, P: OutputPin, W: Wait>(
+    slot: SensorSlot<P, W>,
+    i2c: I,
+) -> Result<ToFSensor<I, P, W>, FailedSensor<I::Error, P, W>>
+where
+    P::Error: Format,
+{
+    try_wake_up(slot, i2c).await.map_err(|mut failed| {
+        hold_in_reset(&mut failed.slot);
+        failed
+    })
+}
+
+/// Drives the XSHUT pin of the slot low. A pin that can't be driven is only logged, the next
+/// power up reports it.
+fn hold_in_reset<P: OutputPin, W>(slot: &mut SensorSlot<P, W>)
+where
+    P::Error: Format,
+{
+    if let Err(e) = slot.config.xshut_pin.set_low() {
+        warn!(
+            "Failed to hold the {} distance sensor in reset: {}",
+            slot.position, e
+        );
+    }
+}
+
+async fn try_wake_up<I: SharedBus, P: OutputPin, W: Wait>(
+    mut slot: SensorSlot<P, W>,
+    mut i2c: I,
+) -> Result<ToFSensor<I, P, W>, FailedSensor<I::Error, P, W>> {
+    if let Err(e) = power_up(
+        &mut slot.config.xshut_pin,
+        &mut i2c,
+        &mut Delay,
+        slot.chip,
+        slot.config.address,
+    )
+    .await
+    {
+        return Err(FailedSensor {
+            slot,
+            error: e.into(),
+        });
+    }
+
+    let SensorSlot {
+        position,
+        chip,
+        config,
+    } = slot;
+    let result = match chip {
+        ChipKind::VL53L0X => VL53L0XSensor::init_new(config, i2c)
+            .await
+            .map(ToFSensor::VL53L0X)
+            .map_err(|e| (BringUpError::VL53L0X(e.error), e.config)),
+        ChipKind::VL53L1X => VL53L1XSensor::init_new(config, i2c)
+            .await
+            .map(ToFSensor::VL53L1X)
+            .map_err(|e| (BringUpError::VL53L1X(e.error), e.config)),
+    };
+    result.map_err(|(error, config)| FailedSensor {
+        slot: SensorSlot {
+            position,
+            chip,
+            config,
+        },
+        error,
+    })
+}
@@ -0,0 +1,301 @@
+use crate::flash::SharedFlash;
+use crate::sensor::distance::{DistanceHandle, DistanceReading, SensorPosition, distance_sensor};
+use crate::sensor::vl53lxx::ToFConfig;
+use defmt::{Format, error, info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Timer, with_timeout};
+use embedded_storage::nor_flash::NorFlash;
+use heapless::Vec;
+use micromath::F32Ext;
+
+/// Number of readings averaged for a calibration
+const SAMPLES: usize = 50;
+/// Readings discarded first, while the filters of the sensor settle on the uncorrected distance
+const SETTLE_SAMPLES: usize = 10;
+/// Readings after which the calibration gives up if too few were usable
+const MAX_READINGS: usize = 4 * SAMPLES;
+const READING_TIMEOUT: Duration = Duration::from_secs(1);
+/// One target per sensor position at most
+pub const MAX_TARGETS: usize = SensorPosition::ALL.len();
+
+/// Offset and crosstalk correction of a ToF sensor.
+///
+/// It is applied by the sensor task to the raw readings, before the filters, the way the ST API
+/// applies the VL53L0X crosstalk: the same correction works for both chips and survives the
+/// restarts and hard resets of the recovery.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Format)]
+pub struct Calibration {
+    /// Added to every distance
+    pub offset_mm: i16,
+    /// Signal rate of the light reflected by the cover glass, 0 when there is none
+    pub crosstalk_mcps: f32,
+}
+
+impl Calibration {
+    /// No correction, the readings are published as the chip reports them
+    pub const NONE: Calibration = Calibration {
+        offset_mm: 0,
+        crosstalk_mcps: 0.0,
+    };
+
+    /// Removes the crosstalk then the offset from a reading
+    pub fn correct(&self, mut reading: DistanceReading) -> DistanceReading {
+        let mut mm = reading.mm as f32;
+        if self.crosstalk_mcps > 0.0 && reading.signal_rate_mcps > self.crosstalk_mcps {
+            mm *= reading.signal_rate_mcps / (reading.signal_rate_mcps - self.crosstalk_mcps);
+        }
+        mm += self.offset_mm as f32;
+        reading.mm = mm.clamp(0.0, u16::MAX as f32) as u16;
+        reading
+    }
+}
+
+/// Where the calibration targets are placed for a sensor
+pub struct CalibrationTarget {
+    pub position: SensorPosition,
+    /// Distance of the target for the offset calibration, ST recommends 100 mm for a white target
+    pub offset_distance_mm: u16,
+    /// Distance of the target for the crosstalk calibration, `None` for a sensor without cover
+    /// glass. It must be far enough for the crosstalk to matter, ST recommends 600 mm.
+    pub crosstalk_distance_mm: Option<u16>,
+}
+
+#[derive(Debug, Format)]
+pub enum CalibrationError {
+    /// The sensor already has the maximum number of subscribers
+    NoSubscriber,
+    /// The sensor stopped publishing
+    Timeout,
+    /// Too few readings were usable, the target is probably out of range
+    NoTarget,
+}
+
+/// Averages the usable readings of a sensor, returns the distance and the signal rate
+async fn measure(handle: DistanceHandle) -> Result<(f32, f32), CalibrationError> {
+    let mut subscription = handle
+        .subscribe()
+        .map_err(|_| CalibrationError::NoSubscriber)?;
+    let mut distance_sum = 0.0;
+    let mut signal_sum = 0.0;
+    let mut samples = 0;
+    for received in 0..MAX_READINGS {
+        let reading = with_timeout(READING_TIMEOUT, subscription.next_measurement())
+            .await
+            .map_err(|_| CalibrationError::Timeout)?
+            .measurement;
+        if received < SETTLE_SAMPLES || !reading.is_usable() {
+            continue;
+        }
+        distance_sum += reading.mm as f32;
+        signal_sum += reading.signal_rate_mcps;
+        samples += 1;
+        if samples == SAMPLES {
+            return Ok((distance_sum / SAMPLES as f32, signal_sum / SAMPLES as f32));
+        }
+    }
+    Err(CalibrationError::NoTarget)
+}
+
+/// Measures a target at `target_mm` without correction and returns the offset to apply
+pub async fn calibrate_offset(
+    handle: DistanceHandle,
+    target_mm: u16,
+) -> Result<Calibration, CalibrationError> {
+    handle
+        .reconfigure(ToFConfig::Calibration(Calibration::NONE))
+        .await;
+    let (distance_mm, _) = measure(handle).await?;
+    Ok(Calibration {
+        offset_mm: (target_mm as f32 - distance_mm).round() as i16,
+        crosstalk_mcps: 0.0,
+    })
+}
+
+/// Measures a target at `target_mm` with the offset of `calibration` applied and returns the
+/// calibration completed with the crosstalk
+pub async fn calibrate_crosstalk(
+    handle: DistanceHandle,
+    calibration: Calibration,
+    target_mm: u16,
+) -> Result<Calibration, CalibrationError> {
+    let offset_only = Calibration {
+        crosstalk_mcps: 0.0,
+        ..calibration
+    };
+    handle
+        .reconfigure(ToFConfig::Calibration(offset_only))
+        .await;
+    let (distance_mm, signal_rate_mcps) = measure(handle).await?;
+    Ok(Calibration {
+        crosstalk_mcps: (signal_rate_mcps * (1.0 - distance_mm / target_mm as f32)).max(0.0),
+        ..offset_only
+    })
+}
+
+/// First bytes of the calibration storage, the rest is ignored if they don't match
+const MAGIC: [u8; 4] = *b"ToF1";
+/// Bytes of a stored calibration: valid flag, padding, offset, crosstalk
+const ENTRY_SIZE: usize = 8;
+/// Size of the stored data, a multiple of the write size of every STM32 flash
+const STORAGE_SIZE: usize = 64;
+const VALID: u8 = 0x01;
+
+/// Calibrations of the distance sensors, persisted in a flash sector reserved for them
+pub struct CalibrationStore<F: NorFlash> {
+    flash: F,
+    /// Offset of the reserved sector from the start of the flash
+    offset: u32,
+    calibrations: [Option<Calibration>; SensorPosition::ALL.len()],
+}
+
+impl<F: NorFlash> CalibrationStore<F> {
+    /// Reads the calibrations saved at `offset`. Starts empty if nothing valid is stored there.
+    pub fn load(mut flash: F, offset: u32) -> Self {
+        let mut calibrations = [None; SensorPosition::ALL.len()];
+        let mut data = [0u8; STORAGE_SIZE];
+        if flash.read(offset, &mut data).is_err() || data[..MAGIC.len()] != MAGIC {
+            info!("No ToF calibration stored");
+        } else {
+            for (calibration, entry) in calibrations
+                .iter_mut()
+                .zip(data[MAGIC.len()..].chunks_exact(ENTRY_SIZE))
+            {
+                if entry[0] == VALID {
+                    *calibration = Some(Calibration {
+                        offset_mm: i16::from_le_bytes([entry[2], entry[3]]),
+                        crosstalk_mcps: f32::from_le_bytes([
+                            entry[4], entry[5], entry[6], entry[7],
+                        ]),
+                    });
+                }
+            }
+        }
+        Self {
+            flash,
+            offset,
+            calibrations,
+        }
+    }
+
+    /// Calibration of the sensor at `position`, `None` if it was never calibrated
+    pub fn get(&self, position: SensorPosition) -> Option<Calibration> {
+        self.calibrations[position as usize]
+    }
+
+    pub fn set(&mut self, position: SensorPosition, calibration: Calibration) {
+        self.calibrations[position as usize] = Some(calibration);
+    }
+
+    /// Erases the reserved sector and writes the calibrations to it
+    pub fn save(&mut self) -> Result<(), F::Error> {
+        let mut data = [0xFF; STORAGE_SIZE];
+        data[..MAGIC.len()].copy_from_slice(&MAGIC);
+        for (calibration, entry) in self
+            .calibrations
+            .iter()
+            .zip(data[MAGIC.len()..].chunks_exact_mut(ENTRY_SIZE))
+        {
+            if let Some(calibration) = calibration {
+                entry[0] = VALID;
+                entry[1] = 0;
+                entry[2..4].copy_from_slice(&calibration.offset_mm.to_le_bytes());
+                entry[4..8].copy_from_slice(&calibration.crosstalk_mcps.to_le_bytes());
+            }
+        }
+        self.flash
+            .erase(self.offset, self.offset + F::ERASE_SIZE as u32)?;
+        self.flash.write(self.offset, &data)
+    }
+}
+
+/// Set by the user button to start a calibration, or to go on with its next step
+static CALIBRATION_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+/// Starts a calibration of the distance sensors, or goes on with its next step. Usable from
+/// interrupts.
+pub fn request_calibration() {
+    CALIBRATION_REQUEST.signal(());
+}
+
+/// Waits for the next request, ignoring the ones made before, e.g. when the button is released
+async fn next_request() {
+    Timer::after(Duration::from_millis(500)).await;
+    CALIBRATION_REQUEST.reset();
+    CALIBRATION_REQUEST.wait().await;
+}
+
+/// Calibrates the distance sensors of `targets` on request, applies the results to the running
+/// sensors and saves them in flash so that they are applied at the next boot.
+///
+/// The offset is calibrated first, with the targets at their offset distance. If some sensors
+/// also have a crosstalk distance, the task then waits for another request, to give time to
+/// move the targets there.
+#[embassy_executor::task]
+pub async fn calibration_task(
+    mut store: CalibrationStore<SharedFlash>,
+    targets: Vec<CalibrationTarget, MAX_TARGETS>,
+) -> ! {
+    loop {
+        CALIBRATION_REQUEST.wait().await;
+        info!("ToF offset calibration started");
+        let mut results: Vec<_, MAX_TARGETS> = Vec::new();
+        for target in targets.iter() {
+            let Some(handle) = distance_sensor(target.position) else {
+                warn!(
+                    "{} distance sensor unavailable, not calibrated",
+                    target.position
+                );
+                continue;
+            };
+            let old = store.get(target.position).unwrap_or_default();
+            match calibrate_offset(handle, target.offset_distance_mm).await {
+                Ok(calibration) => {
+                    info!("{} offset: {} mm", target.position, calibration.offset_mm);
+                    // There is room for every target
+                    let _ = results.push((target, handle, calibration));
+                }
+                Err(e) => {
+                    error!("{} offset calibration failed: {}", target.position, e);
+                    handle.reconfigure(ToFConfig::Calibration(old)).await;
+                }
+            }
+        }
+
+        if results
+            .iter()
+            .any(|(target, ..)| target.crosstalk_distance_mm.is_some())
+        {
+            info!("Move the targets to their crosstalk distance and press the button");
+            next_request().await;
+            for (target, handle, calibration) in results.iter_mut() {
+                let Some(distance_mm) = target.crosstalk_distance_mm else {
+                    continue;
+                };
+                match calibrate_crosstalk(*handle, *calibration, distance_mm).await {
+                    Ok(result) => {
+                        info!(
+                            "{} crosstalk: {} Mcps",
+                            target.position, result.crosstalk_mcps
+                        );
+                        *calibration = result;
+                    }
+                    Err(e) => error!("{} crosstalk calibration failed: {}", target.position, e),
+                }
+            }
+        }
+
+        for (target, handle, calibration) in results {
+            handle
+                .reconfigure(ToFConfig::Calibration(calibration))
+                .await;
+            store.set(target.position, calibration);
+        }
+        match store.save() {
+            Ok(()) => info!("ToF calibration saved"),
+            Err(e) => error!("Failed to save the ToF calibration: {}", e),
+        }
+        // Forget the presses made while calibrating
+        CALIBRATION_REQUEST.reset();
+    }
+}
@@ -1,20 +1,61 @@
-use embassy_stm32::exti::ExtiInput;
-use embassy_stm32::gpio::Output;
+use calibration::Calibration;
+use defmt::Format;
+use micromouse::filter::FilterChain;
+use recovery::RecoveryPolicy;
+use scan::ZoneScan;
 
+pub mod bringup;
+pub mod calibration;
+pub mod recovery;
+pub mod scan;
+pub mod vcsel;
 pub mod vl53l1x;
 pub mod vl53l0x;
 
-/// Configuration for the VL53LXX distance sensors
-pub struct Config {
+pub use micromouse::vl53lxx_bus::ChipKind;
+
+/// Configuration for the VL53LXX distance sensors, `P` being the XSHUT pin and `W` the data-ready
+/// interrupt pin
+pub struct Config<P, W> {
+    /// The timing budget of the VL53L0X is raised to the minimum of its ranging profile, see
+    /// [`TimingConfig::for_profile`]
     pub timing_config: TimingConfig,
-    pub xshut_pin: Output<'static>,
-    pub gpio_interrupt: ExtiInput<'static>,
+    /// Distance mode and region of interest, only supported by the VL53L1X
+    pub ranging_config: RangingConfig,
+    /// I2C address the sensor answers at, already assigned by [`bringup::bring_up`]
+    pub address: u8,
+    /// Held high for the whole life of the sensor, driving it low resets it
+    pub xshut_pin: P,
+    pub gpio_interrupt: W,
+    pub recovery_policy: RecoveryPolicy,
+    /// Applied to the readings before they are published
+    pub filter: FilterChain,
+    /// Correction applied to the raw readings, before the filter
+    pub calibration: Calibration,
+}
+
+/// Error returned by `init_new`, giving the configuration back so that the pins are not lost and
+/// the initialization can be retried
+pub struct InitError<E, P, W> {
+    pub error: E,
+    pub config: Config<P, W>,
+}
+
+/// Settings of a running ToF sensor that can be changed with
+/// [`crate::sensor::command::SensorCommand::Reconfigure`]
+#[derive(Debug, Clone, Copy, Format)]
+pub enum ToFConfig {
+    Timing(TimingConfig),
+    Ranging(RangingConfig),
+    Calibration(Calibration),
 }
 
+#[derive(Debug, Clone, Copy, Format)]
 pub struct TimingConfig {
     /// Measurement timing budget in microseconds (for example: 66000 for 15Hz)
     pub timing_budget_us: u32,
-    /// Inter-measurement period in milliseconds (minimum: 69ms from testing for VL53L1X)
+    /// Inter-measurement period in milliseconds (minimum: 69ms from testing for VL53L1X).
+    /// The VL53L0X measures back-to-back when it is 0 or shorter than the timing budget.
     pub inter_measurement_period_ms: u32,
 }
 
@@ -27,10 +68,140 @@ impl Default for TimingConfig {
     }
 }
 
-trait MeasurementData<S> {
-    fn get_distance_mm(&self) -> i16;
+impl TimingConfig {
+    /// This timing with the budget raised to [`RangingProfile::min_timing_budget_us`] if it is
+    /// shorter, the VL53L0X can't range with the profile otherwise
+    pub fn for_profile(&self, profile: RangingProfile) -> TimingConfig {
+        TimingConfig {
+            timing_budget_us: self.timing_budget_us.max(profile.min_timing_budget_us()),
+            ..*self
+        }
+    }
+}
+
+/// Distance mode of the VL53L1X: the shorter the mode, the less sensitive to ambient light it is
+/// and the shorter the timing budget can be
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub enum DistanceMode {
+    /// Up to 1.3 m, timing budget down to 20 ms
+    Short,
+    /// Up to 3 m, timing budget down to 33 ms
+    Medium,
+    /// Up to 4 m, timing budget down to 33 ms
+    #[default]
+    Long,
+}
+
+/// Rectangle of the 16x16 SPAD array of the VL53L1X that receives the light, narrowing it narrows
+/// the field of view. Columns go from left to right and rows from bottom to top, seen from the
+/// front of the sensor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct Roi {
+    /// Number of columns, from 4 to 16
+    pub width: u8,
+    /// Number of rows, from 4 to 16
+    pub height: u8,
+    /// Column of the centre. For an even width, the centre is between this column and the one
+    /// before it.
+    pub center_x: u8,
+    /// Row of the centre. For an even height, the centre is between this row and the one below it.
+    pub center_y: u8,
+}
+
+impl Roi {
+    /// Smallest side of a ROI supported by the VL53L1X
+    pub const MIN_SIZE: u8 = 4;
+    /// Side of the SPAD array
+    pub const MAX_SIZE: u8 = 16;
+
+    /// The whole SPAD array, 27° field of view
+    pub const FULL: Roi = Roi::centered(Self::MAX_SIZE, Self::MAX_SIZE);
+
+    /// ROI of `width` x `height` SPADs in the middle of the array
+    pub const fn centered(width: u8, height: u8) -> Self {
+        Self {
+            width,
+            height,
+            center_x: Self::MAX_SIZE / 2,
+            center_y: Self::MAX_SIZE / 2,
+        }
+    }
+
+    /// Returns the columns and rows of the ROI as `(left, top, right, bottom)`, inclusive.
+    /// The size is clamped to the supported one and the ROI is moved inside the array if its
+    /// centre is too close to an edge.
+    pub fn bounds(&self) -> (u8, u8, u8, u8) {
+        let width = self.width.clamp(Self::MIN_SIZE, Self::MAX_SIZE);
+        let height = self.height.clamp(Self::MIN_SIZE, Self::MAX_SIZE);
+        let left = self
+            .center_x
+            .saturating_sub(width / 2)
+            .min(Self::MAX_SIZE - width);
+        let bottom = self
+            .center_y
+            .saturating_sub(height / 2)
+            .min(Self::MAX_SIZE - height);
+        (left, bottom + height - 1, left + width - 1, bottom)
+    }
+}
+
+impl Default for Roi {
+    fn default() -> Self {
+        Self::FULL
+    }
+}
+
+/// Ranging profile of the VL53L0X, as defined by ST
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Format)]
+pub enum RangingProfile {
+    /// Up to 1.2 m, 30 ms timing budget
+    #[default]
+    Default,
+    /// Up to 1.2 m with a lower precision, 20 ms timing budget
+    HighSpeed,
+    /// Up to 1.2 m with a precision of about 3%, 200 ms timing budget
+    HighAccuracy,
+    /// Up to 2 m in the dark, accepting weaker signals with longer VCSEL pulses, 33 ms timing
+    /// budget
+    LongRange,
+}
+
+impl RangingProfile {
+    /// Timing budget recommended by ST for the profile, a shorter one loses its precision
+    pub fn min_timing_budget_us(&self) -> u32 {
+        match self {
+            RangingProfile::Default => 30_000,
+            RangingProfile::HighSpeed => 20_000,
+            RangingProfile::HighAccuracy => 200_000,
+            RangingProfile::LongRange => 33_000,
+        }
+    }
 
-    fn get_sigma_mm(&self) -> f64;
+    /// Weakest return signal accepted as a valid measurement
+    pub fn signal_rate_limit_mcps(&self) -> f32 {
+        match self {
+            RangingProfile::LongRange => 0.1,
+            _ => 0.25,
+        }
+    }
+
+    /// VCSEL pulse periods of the pre-range and final-range steps, in PLL clocks
+    pub fn vcsel_periods(&self) -> (u8, u8) {
+        match self {
+            RangingProfile::LongRange => (18, 14),
+            _ => (14, 10),
+        }
+    }
+}
 
-    fn get_status(&self) -> S;
+/// How the sensor ranges. The distance mode, ROI and scan are only supported by the VL53L1X, the
+/// profile only by the VL53L0X.
+#[derive(Debug, Clone, Copy, Default, Format)]
+pub struct RangingConfig {
+    /// Sets the minimum timing budget of the VL53L0X, see [`TimingConfig::for_profile`]
+    pub profile: RangingProfile,
+    pub distance_mode: DistanceMode,
+    pub roi: Roi,
+    /// Zones measured in turn instead of `roi`, `None` to always measure `roi`
+    pub scan: Option<ZoneScan>,
 }
@@ -0,0 +1,89 @@
+use crate::sensor::vl53lxx::TimingConfig;
+use defmt::Format;
+use embassy_time::Duration;
+
+/// How a ToF sensor task reacts to consecutive errors.
+///
+/// Read errors and missing data-ready interrupts both count as errors. Once `restart_after` of them
+/// happened in a row the measurements are restarted, and once `hard_reset_after` happened the
+/// sensor is power cycled through XSHUT and initialized again.
+#[derive(Debug, Clone, Copy, Format)]
+pub struct RecoveryPolicy {
+    /// Time to wait for the data-ready interrupt on top of the measurement period before
+    /// considering that the sensor is stuck
+    pub interrupt_timeout_margin: Duration,
+    pub restart_after: u16,
+    pub hard_reset_after: u16,
+    /// Wait after a failed hard reset before trying again
+    pub hard_reset_backoff: Duration,
+}
+
+impl Default for RecoveryPolicy {
+    fn default() -> Self {
+        Self {
+            interrupt_timeout_margin: Duration::from_millis(100),
+            restart_after: 2,
+            hard_reset_after: 5,
+            hard_reset_backoff: Duration::from_millis(500),
+        }
+    }
+}
+
+/// What a sensor task has to do after an error
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum RecoveryAction {
+    /// Wait for the next measurement
+    Retry,
+    /// Stop and start the measurements again
+    Restart,
+    /// Power cycle the sensor and initialize it from scratch
+    HardReset,
+}
+
+/// Counts the consecutive errors of a sensor and escalates according to its [`RecoveryPolicy`]
+pub struct Recovery {
+    policy: RecoveryPolicy,
+    consecutive_errors: u16,
+}
+
+impl Recovery {
+    pub fn new(policy: RecoveryPolicy) -> Self {
+        Self {
+            policy,
+            consecutive_errors: 0,
+        }
+    }
+
+    pub fn policy(&self) -> &RecoveryPolicy {
+        &self.policy
+    }
+
+    /// Longest time the data-ready interrupt can take with `timing_config`
+    pub fn interrupt_timeout(&self, timing_config: &TimingConfig) -> Duration {
+        let period_us = (timing_config.inter_measurement_period_ms as u64 * 1000)
+            .max(timing_config.timing_budget_us as u64);
+        Duration::from_micros(period_us) + self.policy.interrupt_timeout_margin
+    }
+
+    /// Records a good measurement, the error count starts over
+    pub fn succeeded(&mut self) {
+        self.consecutive_errors = 0;
+    }
+
+    /// Records an error and returns what to do about it
+    pub fn failed(&mut self) -> RecoveryAction {
+        self.consecutive_errors = self.consecutive_errors.saturating_add(1);
+        if self.consecutive_errors >= self.policy.hard_reset_after {
+            RecoveryAction::HardReset
+        } else if self.consecutive_errors >= self.policy.restart_after {
+            RecoveryAction::Restart
+        } else {
+            RecoveryAction::Retry
+        }
+    }
+
+    /// Records a successful hard reset, the sensor gets a fresh start
+    pub fn reset_done(&mut self) {
+        self.consecutive_errors = 0;
+    }
+}
@@ -0,0 +1,210 @@
+use crate::sensor::channel::Timestamped;
+use crate::sensor::distance::{DistanceReading, SensorPosition, distance_sensor};
+use crate::sensor::geometry::{SensorMount, Wall, mount};
+use crate::sensor::vl53lxx::Roi;
+use core::cell::RefCell;
+use core::f32::consts::PI;
+use defmt::{Format, debug, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::WaitResult;
+use embassy_time::{Duration, Timer};
+use micromath::F32Ext;
+
+/// Largest number of zones a [`ZoneScan`] can cycle through
+pub const MAX_ZONES: usize = 4;
+
+/// Angle seen by one column of SPADs, the 16 of them cover 27°
+const SPAD_ANGLE_RAD: f32 = 27.0 / 16.0 * PI / 180.0;
+
+/// ROIs a VL53L1X cycles through, one measurement in each.
+///
+/// Every reading is tagged with the index of its zone in [`DistanceReading::zone`], so that a
+/// single sensor can look in several directions, at the cost of a lower rate per zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub struct ZoneScan {
+    zones: [Roi; MAX_ZONES],
+    len: u8,
+}
+
+impl ZoneScan {
+    /// Cycles through `zones`, keeping the first [`MAX_ZONES`] ones. Returns `None` if `zones` is
+    /// empty.
+    pub fn new(zones: &[Roi]) -> Option<Self> {
+        let len = zones.len().min(MAX_ZONES);
+        if len == 0 {
+            return None;
+        }
+        let mut scan = Self {
+            zones: [Roi::FULL; MAX_ZONES],
+            len: len as u8,
+        };
+        scan.zones[..len].copy_from_slice(&zones[..len]);
+        Some(scan)
+    }
+
+    /// Left and right halves of the SPAD array, `height` rows high: the usual setup to measure the
+    /// angle of a wall
+    pub fn halves(height: u8) -> Self {
+        let half = |center_x| Roi {
+            width: Roi::MAX_SIZE / 2,
+            height,
+            center_x,
+            center_y: Roi::MAX_SIZE / 2,
+        };
+        Self::new(&[half(Roi::MAX_SIZE / 4), half(Roi::MAX_SIZE * 3 / 4)]).unwrap()
+    }
+
+    pub fn zones(&self) -> &[Roi] {
+        &self.zones[..self.len as usize]
+    }
+}
+
+/// Horizontal angle between the axis of the sensor and the centre of `roi`, positive towards the
+/// higher columns
+fn zone_direction(roi: &Roi) -> f32 {
+    let (left, _, right, _) = roi.bounds();
+    let center = (left + right) as f32 / 2.0;
+    let array_center = (Roi::MAX_SIZE - 1) as f32 / 2.0;
+    (center - array_center) * SPAD_ANGLE_RAD
+}
+
+/// Flat wall seen by a scanning sensor
+#[derive(Debug, Clone, Copy, Format)]
+pub struct WallEstimate {
+    /// Angle between the wall and the perpendicular to the sensor axis, positive when the wall
+    /// gets further towards the higher columns. Whether that is left or right depends on how the
+    /// sensor is mounted.
+    pub angle_rad: f32,
+    /// Distance between the sensor and the wall, measured perpendicularly to the wall
+    pub distance_mm: f32,
+}
+
+/// Estimates the wall in front of a scanning sensor from the latest reading of every zone.
+///
+/// Each reading is a point of the wall, in the direction of its zone. A line is fitted through
+/// the fresh points by least squares, so two zones are enough and more of them average the noise.
+pub struct WallEstimator {
+    directions: [f32; MAX_ZONES],
+    latest: [Option<Timestamped<DistanceReading>>; MAX_ZONES],
+    zones: usize,
+    /// Readings older than this are not used
+    max_age: Duration,
+}
+
+impl WallEstimator {
+    pub fn new(scan: &ZoneScan, max_age: Duration) -> Self {
+        let mut directions = [0.0; MAX_ZONES];
+        for (direction, roi) in directions.iter_mut().zip(scan.zones()) {
+            *direction = zone_direction(roi);
+        }
+        Self {
+            directions,
+            latest: [None; MAX_ZONES],
+            zones: scan.zones().len(),
+            max_age,
+        }
+    }
+
+    /// Records a reading of the scanning sensor and returns the wall estimated with it, `None`
+    /// if fewer than two zones have a fresh usable reading
+    pub fn update(&mut self, reading: Timestamped<DistanceReading>) -> Option<WallEstimate> {
+        let zone = reading.measurement.zone? as usize;
+        if zone >= self.zones {
+            return None;
+        }
+        self.latest[zone] = Some(reading).filter(|reading| reading.measurement.is_usable());
+        self.estimate()
+    }
+
+    fn estimate(&self) -> Option<WallEstimate> {
+        let (mut n, mut sum_x, mut sum_y, mut sum_xx, mut sum_xy) = (0.0, 0.0, 0.0, 0.0, 0.0);
+        for (reading, direction) in self.latest[..self.zones].iter().zip(self.directions) {
+            let Some(reading) = reading.filter(|reading| !reading.is_stale(self.max_age)) else {
+                continue;
+            };
+            let distance = reading.measurement.mm as f32;
+            // Across the sensor axis, and along it
+            let x = distance * direction.sin();
+            let y = distance * direction.cos();
+            n += 1.0;
+            sum_x += x;
+            sum_y += y;
+            sum_xx += x * x;
+            sum_xy += x * y;
+        }
+        let denominator = n * sum_xx - sum_x * sum_x;
+        if n < 2.0 || denominator.abs() < f32::EPSILON {
+            return None;
+        }
+        // Wall line y = intercept + slope * x
+        let slope = (n * sum_xy - sum_x * sum_y) / denominator;
+        let intercept = (sum_y - slope * sum_x) / n;
+        let angle_rad = slope.atan();
+        Some(WallEstimate {
+            angle_rad,
+            distance_mm: intercept * angle_rad.cos(),
+        })
+    }
+}
+
+/// Latest wall estimated by [`wall_estimate_task`]
+static WALL_ESTIMATE: Mutex<CriticalSectionRawMutex, RefCell<Option<Timestamped<Wall>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Returns the latest wall estimated in front of the scanning sensor, in the robot frame, `None`
+/// if there was none yet. Check its age before using it: the estimate is not cleared when the wall
+/// goes away.
+pub fn wall_estimate() -> Option<Timestamped<Wall>> {
+    WALL_ESTIMATE.lock(|estimate| *estimate.borrow())
+}
+
+/// Time between two checks for the scanning sensor, while it is not available
+const SENSOR_POLL_PERIOD: Duration = Duration::from_secs(1);
+
+/// Estimates the wall in front of the sensor at `position`, which must scan the zones of `scan`.
+/// The estimate is available from [`wall_estimate`].
+#[embassy_executor::task]
+pub async fn wall_estimate_task(position: SensorPosition, scan: ZoneScan, max_age: Duration) -> ! {
+    let handle = loop {
+        match distance_sensor(position) {
+            Some(handle) => break handle,
+            None => Timer::after(SENSOR_POLL_PERIOD).await,
+        }
+    };
+    let mount = mount(position).copied().unwrap_or_else(|| {
+        warn!(
+            "No geometry for the {} sensor, walls are seen from it",
+            position
+        );
+        SensorMount {
+            position,
+            x_mm: 0.0,
+            y_mm: 0.0,
+            heading_rad: 0.0,
+            columns_to_left: false,
+        }
+    });
+    let mut subscription = handle.subscribe().unwrap();
+    let mut estimator = WallEstimator::new(&scan, max_age);
+    loop {
+        match subscription.next().await {
+            WaitResult::Message(reading) => {
+                if let Some(estimate) = estimator.update(reading) {
+                    debug!(
+                        "Wall: {} rad at {} mm",
+                        estimate.angle_rad, estimate.distance_mm
+                    );
+                    let estimate = Timestamped {
+                        measurement: mount.wall_from_estimate(&estimate),
+                        timestamp: reading.timestamp,
+                    };
+                    WALL_ESTIMATE.lock(|latest| *latest.borrow_mut() = Some(estimate));
+                }
+            }
+            WaitResult::Lagged(count) => {
+                warn!("Wall estimator lagged, {} readings missed", count);
+            }
+        }
+    }
+}
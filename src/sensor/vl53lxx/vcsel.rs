@@ -0,0 +1,197 @@
+use embassy_time::{Duration, Instant};
+use embedded_hal::i2c::I2c;
+use micromouse::vl53lxx_bus::DEFAULT_ADDRESS;
+use vl53l0x::Error;
+
+const SYSRANGE_START: u8 = 0x00;
+const SYSTEM_SEQUENCE_CONFIG: u8 = 0x01;
+const SYSTEM_INTERRUPT_CLEAR: u8 = 0x0B;
+const RESULT_INTERRUPT_STATUS: u8 = 0x13;
+const ALGO_PHASECAL_CONFIG_TIMEOUT: u8 = 0x30;
+const GLOBAL_CONFIG_VCSEL_WIDTH: u8 = 0x32;
+const MSRC_CONFIG_TIMEOUT_MACROP: u8 = 0x46;
+const FINAL_RANGE_CONFIG_VALID_PHASE_LOW: u8 = 0x47;
+const FINAL_RANGE_CONFIG_VALID_PHASE_HIGH: u8 = 0x48;
+const PRE_RANGE_CONFIG_VCSEL_PERIOD: u8 = 0x50;
+const PRE_RANGE_CONFIG_TIMEOUT_MACROP_HI: u8 = 0x51;
+const PRE_RANGE_CONFIG_VALID_PHASE_LOW: u8 = 0x56;
+const PRE_RANGE_CONFIG_VALID_PHASE_HIGH: u8 = 0x57;
+const FINAL_RANGE_CONFIG_VCSEL_PERIOD: u8 = 0x70;
+const FINAL_RANGE_CONFIG_TIMEOUT_MACROP_HI: u8 = 0x71;
+/// Writing 1 to it gives access to a second page of registers, 0 goes back to the first one
+const PAGE_SELECT: u8 = 0xFF;
+/// In the second page
+const ALGO_PHASECAL_LIM: u8 = 0x30;
+
+/// SYSTEM_SEQUENCE_CONFIG
+const SEQUENCE_PHASE_CALIBRATION: u8 = 0x02;
+const SEQUENCE_PRE_RANGE: u8 = 0x40;
+
+/// Longest time the phase calibration takes
+const PHASE_CALIBRATION_TIMEOUT: Duration = Duration::from_millis(50);
+
+/// Sets the VCSEL pulse periods of the pre-range and final-range steps of a VL53L0X, in PLL
+/// clocks, keeping the time the steps last. The driver crate can't change them.
+///
+/// Ported from the Pololu library the driver is based on. The sensor must not be ranging, and the
+/// timing budget must be set again then [`calibrate_phase`] called afterwards.
+///
+/// # Panics
+///
+/// If a period is not supported: 12, 14, 16 or 18 for the pre-range, 8, 10, 12 or 14 for the
+/// final range.
+pub fn set_vcsel_periods<I: I2c>(
+    i2c: &mut I,
+    pre_range: u8,
+    final_range: u8,
+) -> Result<(), Error<I::Error>> {
+    set_pre_range_period(i2c, pre_range)?;
+    set_final_range_period(i2c, final_range)
+}
+
+fn set_pre_range_period<I: I2c>(i2c: &mut I, period: u8) -> Result<(), Error<I::Error>> {
+    let phase_high = match period {
+        12 => 0x18,
+        14 => 0x30,
+        16 => 0x40,
+        18 => 0x50,
+        _ => panic!("Unsupported pre-range VCSEL period: {}", period),
+    };
+
+    let old_period = decode_period(read(i2c, PRE_RANGE_CONFIG_VCSEL_PERIOD)?);
+    let msrc_us = mclks_to_us(
+        read(i2c, MSRC_CONFIG_TIMEOUT_MACROP)? as u32 + 1,
+        old_period,
+    );
+    let pre_range_mclks = decode_timeout(read_u16(i2c, PRE_RANGE_CONFIG_TIMEOUT_MACROP_HI)?);
+    let pre_range_us = mclks_to_us(pre_range_mclks, old_period);
+
+    write(i2c, PRE_RANGE_CONFIG_VALID_PHASE_HIGH, phase_high)?;
+    write(i2c, PRE_RANGE_CONFIG_VALID_PHASE_LOW, 0x08)?;
+    write(i2c, PRE_RANGE_CONFIG_VCSEL_PERIOD, encode_period(period))?;
+    write_u16(
+        i2c,
+        PRE_RANGE_CONFIG_TIMEOUT_MACROP_HI,
+        encode_timeout(us_to_mclks(pre_range_us, period)),
+    )?;
+    let msrc_mclks = us_to_mclks(msrc_us, period).clamp(1, 256);
+    write(i2c, MSRC_CONFIG_TIMEOUT_MACROP, (msrc_mclks - 1) as u8)?;
+    Ok(())
+}
+
+fn set_final_range_period<I: I2c>(i2c: &mut I, period: u8) -> Result<(), Error<I::Error>> {
+    let (phase_high, vcsel_width, phasecal_timeout, phasecal_limit) = match period {
+        8 => (0x10, 0x02, 0x0C, 0x30),
+        10 => (0x28, 0x03, 0x09, 0x20),
+        12 => (0x38, 0x03, 0x08, 0x20),
+        14 => (0x48, 0x03, 0x07, 0x20),
+        _ => panic!("Unsupported final-range VCSEL period: {}", period),
+    };
+
+    // The timeout of the final range includes the one of the pre-range when it is enabled
+    let pre_range_mclks = if read(i2c, SYSTEM_SEQUENCE_CONFIG)? & SEQUENCE_PRE_RANGE != 0 {
+        decode_timeout(read_u16(i2c, PRE_RANGE_CONFIG_TIMEOUT_MACROP_HI)?)
+    } else {
+        0
+    };
+    let old_period = decode_period(read(i2c, FINAL_RANGE_CONFIG_VCSEL_PERIOD)?);
+    let final_range_mclks = decode_timeout(read_u16(i2c, FINAL_RANGE_CONFIG_TIMEOUT_MACROP_HI)?)
+        .saturating_sub(pre_range_mclks);
+    let final_range_us = mclks_to_us(final_range_mclks, old_period);
+
+    write(i2c, FINAL_RANGE_CONFIG_VALID_PHASE_HIGH, phase_high)?;
+    write(i2c, FINAL_RANGE_CONFIG_VALID_PHASE_LOW, 0x08)?;
+    write(i2c, GLOBAL_CONFIG_VCSEL_WIDTH, vcsel_width)?;
+    write(i2c, ALGO_PHASECAL_CONFIG_TIMEOUT, phasecal_timeout)?;
+    write(i2c, PAGE_SELECT, 0x01)?;
+    write(i2c, ALGO_PHASECAL_LIM, phasecal_limit)?;
+    write(i2c, PAGE_SELECT, 0x00)?;
+    write(i2c, FINAL_RANGE_CONFIG_VCSEL_PERIOD, encode_period(period))?;
+    write_u16(
+        i2c,
+        FINAL_RANGE_CONFIG_TIMEOUT_MACROP_HI,
+        encode_timeout(us_to_mclks(final_range_us, period) + pre_range_mclks),
+    )?;
+    Ok(())
+}
+
+/// Calibrates the phase of the VCSEL, required after changing its periods. The sensor must not be
+/// ranging.
+pub fn calibrate_phase<I: I2c>(i2c: &mut I) -> Result<(), Error<I::Error>> {
+    let sequence = read(i2c, SYSTEM_SEQUENCE_CONFIG)?;
+    write(i2c, SYSTEM_SEQUENCE_CONFIG, SEQUENCE_PHASE_CALIBRATION)?;
+    write(i2c, SYSRANGE_START, 0x01)?;
+    let deadline = Instant::now() + PHASE_CALIBRATION_TIMEOUT;
+    while read(i2c, RESULT_INTERRUPT_STATUS)? & 0x07 == 0 {
+        if Instant::now() > deadline {
+            return Err(Error::Timeout);
+        }
+    }
+    write(i2c, SYSTEM_INTERRUPT_CLEAR, 0x01)?;
+    write(i2c, SYSRANGE_START, 0x00)?;
+    write(i2c, SYSTEM_SEQUENCE_CONFIG, sequence)?;
+    Ok(())
+}
+
+/// Period of the VCSEL in PLL clocks from the value of its register
+fn decode_period(register: u8) -> u8 {
+    (register + 1) << 1
+}
+
+fn encode_period(period: u8) -> u8 {
+    (period >> 1) - 1
+}
+
+/// Timeouts are stored as `(lsb << msb) + 1` macro periods, with the MSB in the high byte
+fn decode_timeout(register: u16) -> u32 {
+    (u32::from(register & 0xFF) << (register >> 8)) + 1
+}
+
+fn encode_timeout(mclks: u32) -> u16 {
+    if mclks == 0 {
+        return 0;
+    }
+    let mut lsb = mclks - 1;
+    let mut msb = 0;
+    while lsb > 0xFF {
+        lsb >>= 1;
+        msb += 1;
+    }
+    (msb << 8) | lsb as u16
+}
+
+/// Length of a macro period in nanoseconds, for a VCSEL period in PLL clocks
+fn macro_period_ns(period: u8) -> u64 {
+    (2304 * period as u64 * 1655 + 500) / 1000
+}
+
+fn mclks_to_us(mclks: u32, period: u8) -> u32 {
+    let macro_period_ns = macro_period_ns(period);
+    ((mclks as u64 * macro_period_ns + macro_period_ns / 2) / 1000) as u32
+}
+
+fn us_to_mclks(us: u32, period: u8) -> u32 {
+    let macro_period_ns = macro_period_ns(period);
+    ((us as u64 * 1000 + macro_period_ns / 2) / macro_period_ns) as u32
+}
+
+fn read<I: I2c>(i2c: &mut I, register: u8) -> Result<u8, I::Error> {
+    let mut value = [0];
+    i2c.write_read(DEFAULT_ADDRESS, &[register], &mut value)?;
+    Ok(value[0])
+}
+
+fn read_u16<I: I2c>(i2c: &mut I, register: u8) -> Result<u16, I::Error> {
+    let mut value = [0; 2];
+    i2c.write_read(DEFAULT_ADDRESS, &[register], &mut value)?;
+    Ok(u16::from_be_bytes(value))
+}
+
+fn write<I: I2c>(i2c: &mut I, register: u8, value: u8) -> Result<(), I::Error> {
+    i2c.write(DEFAULT_ADDRESS, &[register, value])
+}
+
+fn write_u16<I: I2c>(i2c: &mut I, register: u8, value: u16) -> Result<(), I::Error> {
+    let [high, low] = value.to_be_bytes();
+    i2c.write(DEFAULT_ADDRESS, &[register, high, low])
+}
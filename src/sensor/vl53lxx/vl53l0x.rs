@@ -1,122 +1,340 @@
+use crate::i2c_bus::SharedBus;
 use crate::sensor::Sensor;
-use crate::sensor::vl53lxx::Config;
+use crate::sensor::channel::SensorHandle;
+use crate::sensor::command::{Event, RunState, SensorCommand, next_event};
+use crate::sensor::distance::DistanceReading;
+use crate::sensor::health::{ErrorKind, HealthEvent};
+use crate::sensor::vl53lxx::bringup::BringUpError;
+use crate::sensor::vl53lxx::calibration::Calibration;
+use crate::sensor::vl53lxx::recovery::{Recovery, RecoveryAction};
+use crate::sensor::vl53lxx::vcsel::{calibrate_phase, set_vcsel_periods};
+use crate::sensor::vl53lxx::{
+    ChipKind, Config, InitError, RangingProfile, TimingConfig, ToFConfig,
+};
+use core::convert::Infallible;
 use core::fmt::Debug;
-use defmt::{Format, debug, warn};
-use embassy_executor::{SpawnError, Spawner};
-use embassy_stm32::i2c;
-use embassy_stm32::i2c::{I2c, Master};
-use embassy_stm32::mode::Async;
-use embassy_time::{Duration, Timer};
-use embedded_hal_bus::i2c::RefCellDevice;
-use vl53l0x::RangeStatus::{PhaseFail, SignalFail};
+use defmt::{Format, debug, error, info, warn};
+use embassy_executor::{SpawnError, SpawnToken, Spawner};
+use embassy_time::{Delay, Timer};
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::digital::Wait;
+use micromouse::filter::FilterChain;
+use micromouse::vl53lxx_bus::{RemappedI2c, hard_reset, read_vl53l0x_range};
 use vl53l0x::*;
 
 /// VL53L0X Time-of-Flight distance sensor implementation
 ///
 /// This sensor uses a shared I2C bus through a mutex, allowing multiple sensors
-/// to share the same I2C peripheral safely.
-pub struct VL53L0XSensor {
-    device: VL53L0x<I>,
-    gpio_interrupt: embassy_stm32::exti::ExtiInput<'static>,
-    last_data: MeasurementData,
-    on_new_measurement: Option<&'static dyn Fn(&MeasurementData)>,
+/// to share the same I2C peripheral safely. The driver crate is only used to configure the sensor,
+/// the measurements are read asynchronously so that the executor keeps running during transfers.
+/// `P` is the XSHUT pin and `W` the data-ready interrupt pin.
+pub struct VL53L0XSensor<I: SharedBus, P: OutputPin, W: Wait> {
+    device: VL53L0x<RemappedI2c<I>>,
+    /// Spare handle on the bus, used for the async reads and to create the device again after a
+    /// hard reset
+    bus: I,
+    address: u8,
+    /// Timing asked for, see [`Self::timing`] for the one programmed
+    timing_config: TimingConfig,
+    profile: RangingProfile,
+    xshut_pin: P,
+    gpio_interrupt: W,
+    recovery: Recovery,
+    calibration: Calibration,
+    filter: FilterChain,
+    handle: SensorHandle<DistanceReading, ToFConfig>,
 }
 
 #[derive(Debug, Format)]
-pub enum StartError {
+pub enum StartError<E> {
     I2cError(E),
     SpawnError(SpawnError),
 }
 
-#[derive(Debug, Format)]
-pub struct MeasurementData {
-    pub distance_mm: u16,
-    pub status: RangeStatus,
+/// Timing budget programmed for `timing_config` with `profile`, warning if it had to be raised
+fn timing_budget_us(timing_config: &TimingConfig, profile: RangingProfile) -> u32 {
+    let timing_budget_us = timing_config.for_profile(profile).timing_budget_us;
+    if timing_budget_us != timing_config.timing_budget_us {
+        warn!(
+            "VL53L0X timing budget of {} us too short for the {} profile, raised to {} us",
+            timing_config.timing_budget_us, profile, timing_budget_us
+        );
+    }
+    timing_budget_us
 }
 
-impl Default for MeasurementData {
-    fn default() -> Self {
-        Self {
-            distance_mm: 0,
-            status: RangeStatus::None,
+impl<I: SharedBus, P: OutputPin, W: Wait> VL53L0XSensor<I, P, W> {
+    /// Initializes a sensor that was powered up and moved to `config.address` by
+    /// [`crate::sensor::vl53lxx::bringup::bring_up`].
+    pub(crate) async fn init_new(
+        config: Config<P, W>,
+        mut bus: I,
+    ) -> Result<Self, InitError<Error<I::Error>, P, W>> {
+        bus.acquire().await;
+        let profile = config.ranging_config.profile;
+        let timing_config = config.timing_config;
+        match Self::init_device(&timing_config, profile, config.address, bus.clone()) {
+            Ok(device) => Ok(Self {
+                device,
+                bus,
+                address: config.address,
+                timing_config,
+                profile,
+                xshut_pin: config.xshut_pin,
+                gpio_interrupt: config.gpio_interrupt,
+                recovery: Recovery::new(config.recovery_policy),
+                calibration: config.calibration,
+                filter: config.filter,
+                handle: SensorHandle::new(),
+            }),
+            Err(error) => Err(InitError { error, config }),
+        }
+    }
+
+    fn init_device(
+        timing_config: &TimingConfig,
+        profile: RangingProfile,
+        address: u8,
+        i2c: I,
+    ) -> Result<VL53L0x<RemappedI2c<I>>, Error<I::Error>> {
+        let mut device = VL53L0x::new(RemappedI2c::new(i2c.clone(), address))?;
+        Self::configure_ranging(&mut device, i2c, address, profile, timing_config)?;
+        Ok(device)
+    }
+
+    /// Programs the signal rate limit and the VCSEL periods of `profile`, then the timing budget,
+    /// which depends on them and can't be shorter than the minimum of the profile. The sensor must
+    /// not be ranging and the bus must have been acquired.
+    fn configure_ranging(
+        device: &mut VL53L0x<RemappedI2c<I>>,
+        i2c: I,
+        address: u8,
+        profile: RangingProfile,
+        timing_config: &TimingConfig,
+    ) -> Result<(), Error<I::Error>> {
+        let mut registers = RemappedI2c::new(i2c, address);
+        device.set_signal_rate_limit(profile.signal_rate_limit_mcps())?;
+        let (pre_range, final_range) = profile.vcsel_periods();
+        set_vcsel_periods(&mut registers, pre_range, final_range)?;
+        device.set_measurement_timing_budget(timing_budget_us(timing_config, profile))?;
+        calibrate_phase(&mut registers)
+    }
+
+    /// Timing the sensor measures with, the budget raised to the minimum of its profile
+    fn timing(&self) -> TimingConfig {
+        self.timing_config.for_profile(self.profile)
+    }
+
+    /// Starts the continuous measurements, timed by the inter-measurement period.
+    /// The bus must have been acquired.
+    fn start_ranging(&mut self) -> Result<(), Error<I::Error>> {
+        self.device
+            .start_continuous(self.timing_config.inter_measurement_period_ms)
+    }
+
+    /// Stops and starts the continuous measurements again
+    async fn restart(&mut self) -> Result<(), Error<I::Error>> {
+        self.bus.acquire().await;
+        self.device.stop_continuous()?;
+        self.start_ranging()
+    }
+
+    /// Power cycles the sensor and initializes it again, measuring if `state` requires it
+    async fn hard_reset(
+        &mut self,
+        state: RunState,
+    ) -> Result<(), BringUpError<I::Error, P::Error>> {
+        hard_reset(
+            &mut self.xshut_pin,
+            &mut self.bus,
+            &mut Delay,
+            ChipKind::VL53L0X,
+            self.address,
+        )
+        .await?;
+        self.bus.acquire().await;
+        self.device = Self::init_device(
+            &self.timing_config,
+            self.profile,
+            self.address,
+            self.bus.clone(),
+        )
+        .map_err(BringUpError::VL53L0X)?;
+        if state.is_measuring() {
+            self.start_ranging().map_err(BringUpError::VL53L0X)?;
+        }
+        Ok(())
+    }
+
+    /// Puts the device in the state required by `command`, returns the new state of the task.
+    /// The bus must have been acquired.
+    fn apply_command(
+        &mut self,
+        command: SensorCommand<ToFConfig>,
+        state: RunState,
+    ) -> Result<RunState, Error<I::Error>> {
+        let new_state = state.after(&command);
+        match command {
+            SensorCommand::Reconfigure(ToFConfig::Timing(timing_config)) => {
+                if state.is_measuring() {
+                    self.device.stop_continuous()?;
+                }
+                self.device.set_measurement_timing_budget(timing_budget_us(
+                    &timing_config,
+                    self.profile,
+                ))?;
+                self.timing_config = timing_config;
+                if new_state.is_measuring() {
+                    self.start_ranging()?;
+                }
+            }
+            // Only the profile applies to the VL53L0X
+            SensorCommand::Reconfigure(ToFConfig::Ranging(ranging_config)) => {
+                if state.is_measuring() {
+                    self.device.stop_continuous()?;
+                }
+                let profile = ranging_config.profile;
+                Self::configure_ranging(
+                    &mut self.device,
+                    self.bus.clone(),
+                    self.address,
+                    profile,
+                    &self.timing_config,
+                )?;
+                self.profile = profile;
+                if new_state.is_measuring() {
+                    self.start_ranging()?;
+                }
+            }
+            // Applied on the host, the device keeps measuring
+            SensorCommand::Reconfigure(ToFConfig::Calibration(calibration)) => {
+                self.calibration = calibration;
+            }
+            _ if new_state.is_measuring() && !state.is_measuring() => {
+                self.start_ranging()?;
+            }
+            _ if !new_state.is_measuring() && state.is_measuring() => {
+                self.device.stop_continuous()?;
+            }
+            _ => {}
         }
+        Ok(new_state)
     }
 }
 
-// I hate not being able to use generics due to the embassy task
-type I = RefCellDevice<'static, I2c<'static, Async, Master>>;
-type E = i2c::Error;
-
-impl VL53L0XSensor {
-    pub(crate) async fn init_new(mut config: Config, i2c: I) -> Result<Self, Error<E>> {
-        // Toggle XSHUT pin to reset the device
-        debug!("  Toggling XSHUT pin...");
-        config.xshut_pin.set_low();
-        Timer::after(Duration::from_millis(10)).await;
-
-        // Wait for device boot
-        config.xshut_pin.set_high();
-        Timer::after(Duration::from_millis(10)).await;
-        debug!("  XSHUT toggled");
-
-        let mut device = VL53L0x::new(i2c)?;
-        device.set_address(0x30)?;
-
-        device.set_measurement_timing_budget(config.timing_config.timing_budget_us)?;
-
-        Ok(Self {
-            device,
-            gpio_interrupt: config.gpio_interrupt,
-            last_data: MeasurementData::default(),
-            on_new_measurement: None,
-        })
+impl<I: SharedBus, P: OutputPin, W: Wait> VL53L0XSensor<I, P, W>
+where
+    I::Error: Format,
+    P::Error: Format,
+{
+    /// Records an error and escalates according to the recovery policy of the sensor
+    async fn recover(&mut self, state: RunState) {
+        match self.recovery.failed() {
+            RecoveryAction::Retry => {}
+            RecoveryAction::Restart => {
+                self.handle.record(HealthEvent::Restart);
+                info!("Restarting VL53L0X measurements");
+                if let Err(e) = self.restart().await {
+                    warn!("VL53L0X restart failed: {}", e);
+                    self.handle.record(HealthEvent::Error(ErrorKind::Recovery));
+                }
+            }
+            RecoveryAction::HardReset => {
+                warn!("Resetting VL53L0X");
+                self.handle.record(HealthEvent::HardReset);
+                match self.hard_reset(state).await {
+                    Ok(()) => {
+                        info!("VL53L0X reset and initialized again");
+                        self.recovery.reset_done();
+                    }
+                    Err(e) => {
+                        error!("VL53L0X reset failed: {}", e);
+                        self.handle.record(HealthEvent::Error(ErrorKind::Recovery));
+                        Timer::after(self.recovery.policy().hard_reset_backoff).await;
+                    }
+                }
+            }
+        }
     }
 }
 
-impl Sensor<MeasurementData, StartError> for VL53L0XSensor {
-    async fn start_continuous_measurement(
+impl<I: SharedBus + 'static, P: OutputPin + 'static, W: Wait + 'static>
+    Sensor<DistanceReading, StartError<I::Error>, ToFConfig> for VL53L0XSensor<I, P, W>
+where
+    I::Error: Format,
+    P::Error: Format,
+    W::Error: Format,
+{
+    async fn start_continuous_measurement<S>(
         &'static mut self,
         spawner: &mut Spawner,
-        callable: &'static dyn Fn(&MeasurementData),
-    ) -> Result<(), StartError> {
-        self.on_new_measurement = Some(callable);
-        self.device
-            .start_continuous(0)
-            .map_err(|e| StartError::I2cError(e))?;
+        task: impl FnOnce(&'static mut Self) -> SpawnToken<S>,
+    ) -> Result<SensorHandle<DistanceReading, ToFConfig>, StartError<I::Error>> {
+        let handle = self.handle;
+        self.bus.acquire().await;
+        self.start_ranging().map_err(|e| StartError::I2cError(e))?;
         spawner
-            .spawn(distance_sensor_task(self))
+            .spawn(task(self))
             .map_err(|e| StartError::SpawnError(e))?;
-        Ok(())
+        Ok(handle)
     }
 
-    fn get_latest_measurement(&self) -> &MeasurementData {
-        &self.last_data
+    fn handle(&self) -> SensorHandle<DistanceReading, ToFConfig> {
+        self.handle
     }
-}
 
-#[embassy_executor::task]
-async fn distance_sensor_task(self_: &'static mut VL53L0XSensor) -> ! {
-    debug!("Distance sensor task running");
-
-    loop {
-        self_.gpio_interrupt.wait_for_falling_edge().await;
-
-        match self_.device.get_range_with_status_blocking() {
-            Ok((distance_mm, status)) => {
-                self_.last_data = MeasurementData {
-                    distance_mm,
-                    status,
-                };
-                if status != SignalFail && status != PhaseFail {
-                    // debug!("VL53L0X Distance: {} mm", distance_mm);
-                    self_.on_new_measurement.unwrap()(&self_.last_data);
-                    // if let Some(callback) = &self_.on_new_measurement {
-                    //     callback(&self_.last_data);
-                    // }
+    async fn run(&mut self) -> Infallible {
+        debug!("Distance sensor task running");
+        let mut state = RunState::Continuous;
+
+        loop {
+            let timeout = self.recovery.interrupt_timeout(&self.timing());
+            let event =
+                next_event(self.handle, &mut self.gpio_interrupt, state, Some(timeout)).await;
+            let timestamp = match event {
+                Event::DataReady(timestamp) => timestamp,
+                Event::Timeout => {
+                    warn!("VL53L0X data-ready interrupt timed out");
+                    self.handle.record(HealthEvent::InterruptTimeout);
+                    self.recover(state).await;
+                    continue;
+                }
+                Event::Command(command) => {
+                    self.bus.acquire().await;
+                    match self.apply_command(command, state) {
+                        Ok(new_state) => state = new_state,
+                        Err(e) => {
+                            warn!("VL53L0X command {} failed: {}", command, e);
+                            self.handle.record(HealthEvent::Error(ErrorKind::Command));
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            // Read without blocking the executor
+            match read_vl53l0x_range(&mut self.bus, self.address).await {
+                Ok(reading) => {
+                    self.recovery.succeeded();
+                    // debug!("VL53L0X Distance: {} mm", reading.mm);
+                    let reading = self.filter.apply(self.calibration.correct(reading));
+                    let usable = reading.is_usable();
+                    self.handle.publish_with_quality(reading, timestamp, usable);
+                }
+                Err(e) => {
+                    warn!("VL53L0X read error: {}", e);
+                    self.handle.record(HealthEvent::Error(ErrorKind::Read));
+                    self.recover(state).await;
+                    continue;
                 }
             }
-            Err(e) => {
-                warn!("VL53L0X read error: {}", e);
+
+            if state == RunState::SingleShot {
+                self.bus.acquire().await;
+                match self.device.stop_continuous() {
+                    Ok(()) => state = RunState::Stopped,
+                    Err(e) => warn!("VL53L0X failed to stop after single shot: {}", e),
+                }
             }
         }
     }
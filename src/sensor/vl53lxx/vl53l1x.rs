@@ -1,46 +1,97 @@
+use crate::i2c_bus::SharedBus;
 use crate::sensor::Sensor;
-use crate::sensor::vl53lxx::{Config, MeasurementData};
-use alloc::format;
-use alloc::string::String;
-use defmt::{debug, error, info, warn};
-use embassy_executor::{SpawnError, Spawner};
-use embassy_stm32::i2c;
-use embassy_stm32::i2c::{I2c, Master};
-use embassy_stm32::mode::Async;
+use crate::sensor::channel::SensorHandle;
+use crate::sensor::command::{Event, RunState, SensorCommand, next_event};
+use crate::sensor::distance::{DistanceReading, RangeQuality};
+use crate::sensor::health::{ErrorKind, HealthEvent};
+use crate::sensor::vl53lxx::bringup::BringUpError;
+use crate::sensor::vl53lxx::calibration::Calibration;
+use crate::sensor::vl53lxx::recovery::{Recovery, RecoveryAction};
+use crate::sensor::vl53lxx::scan::MAX_ZONES;
+use crate::sensor::vl53lxx::{
+    ChipKind, Config, DistanceMode, InitError, RangingConfig, Roi, TimingConfig, ToFConfig,
+};
+use core::convert::Infallible;
+use defmt::{Format, debug, error, info, warn};
+use embassy_executor::{SpawnError, SpawnToken, Spawner};
 use embassy_time::{Delay, Duration, Timer};
-use embedded_hal_bus::i2c::RefCellDevice;
-use vl53l1::RangeStatus::SIGNAL_FAIL;
+use embedded_hal::digital::OutputPin;
+use embedded_hal_async::digital::Wait;
+use embedded_hal_async::i2c::I2c as AsyncI2c;
+use heapless::Vec;
+use micromouse::filter::FilterChain;
+use micromouse::vl53lxx_bus::{RemappedI2c, hard_reset};
 use vl53l1::*;
 
-pub struct VL53L1XSensor {
+/// VL53L1X Time-of-Flight distance sensor.
+///
+/// The ST API port configures the sensor, the measurements are read asynchronously so that the
+/// executor keeps running during transfers. `P` is the XSHUT pin and `W` the data-ready interrupt
+/// pin.
+pub struct VL53L1XSensor<I: SharedBus, P: OutputPin, W: Wait> {
     device: Device,
-    gpio_interrupt: embassy_stm32::exti::ExtiInput<'static>,
-    i2c: I,
-    last_data: RangingMeasurementData,
-    recovery_mode: bool,
-    on_new_measurement: Option<&'static dyn Fn(&RangingMeasurementData)>,
+    xshut_pin: P,
+    gpio_interrupt: W,
+    i2c: RemappedI2c<I>,
+    /// Spare handle on the bus, used for the async reads and to move the sensor back to its
+    /// address after a hard reset
+    bus: I,
+    address: u8,
+    timing_config: TimingConfig,
+    ranging_config: RangingConfig,
+    recovery: Recovery,
+    calibration: Calibration,
+    /// One chain per zone, so that the zones of a scan don't mix their histories
+    filters: Vec<FilterChain, MAX_ZONES>,
+    /// Zone being measured when the sensor scans, 0 otherwise
+    zone: usize,
+    handle: SensorHandle<DistanceReading, ToFConfig>,
 }
 
-// I hate not being able to use generics due to the embassy task
-type I = RefCellDevice<'static, I2c<'static, Async, Master>>;
-type E = i2c::Error;
-
-impl VL53L1XSensor {
-    pub(crate) async fn init_new(mut config: Config, mut i2c: I) -> Result<Self, Error<i2c::Error>> {
+impl<I: SharedBus, P: OutputPin, W: Wait> VL53L1XSensor<I, P, W> {
+    /// Initializes a sensor that was powered up and moved to `config.address` by
+    /// [`crate::sensor::vl53lxx::bringup::bring_up`], and starts its measurements.
+    pub(crate) async fn init_new(
+        config: Config<P, W>,
+        i2c: I,
+    ) -> Result<Self, InitError<Error<I::Error>, P, W>> {
         info!("Initializing VL53L1X distance sensor");
+        let mut bus = i2c.clone();
+        bus.acquire().await;
+        let mut i2c = RemappedI2c::new(i2c, config.address);
+        match Self::init_device(&config.timing_config, &config.ranging_config, &mut i2c) {
+            Ok(device) => {
+                info!("VL53L1X initialization complete");
+                Ok(Self {
+                    device,
+                    xshut_pin: config.xshut_pin,
+                    gpio_interrupt: config.gpio_interrupt,
+                    i2c,
+                    bus,
+                    address: config.address,
+                    timing_config: config.timing_config,
+                    ranging_config: config.ranging_config,
+                    recovery: Recovery::new(config.recovery_policy),
+                    calibration: config.calibration,
+                    filters: zone_filters(&config.filter, &config.ranging_config),
+                    zone: 0,
+                    handle: SensorHandle::new(),
+                })
+            }
+            Err(error) => Err(InitError { error, config }),
+        }
+    }
 
-        // Toggle XSHUT pin to reset the device
-        info!("  Toggling XSHUT pin...");
-        config.xshut_pin.set_low();
-        Timer::after(Duration::from_millis(10)).await;
-        config.xshut_pin.set_high();
-        Timer::after(Duration::from_millis(10)).await;
-
+    fn init_device(
+        timing_config: &TimingConfig,
+        ranging_config: &RangingConfig,
+        i2c: &mut RemappedI2c<I>,
+    ) -> Result<Device, Error<I::Error>> {
         let mut device = Device::default();
 
         // Initialize the sensor
         info!("  Data init...");
-        data_init(&mut device, &mut i2c)?;
+        data_init(&mut device, i2c)?;
 
         info!("  Static init...");
         static_init(&mut device)?;
@@ -48,146 +99,359 @@ impl VL53L1XSensor {
         info!("  Setting preset mode...");
         set_preset_mode(&mut device, PresetMode::Autonomous)?;
 
-        // Set full field of view
-        info!("  Setting ROI...");
-        set_user_roi(
-            &mut device,
-            UserRoi {
-                top_left_x: 0,
-                top_left_y: 15,
-                bot_right_x: 15,
-                bot_right_y: 0,
-            },
-        )?;
+        info!("  Setting distance mode and ROI...");
+        set_ranging_config(&mut device, ranging_config)?;
 
         info!("  Setting timing budget and inter-measurement period...");
-        set_measurement_timing_budget_micro_seconds(
-            &mut device,
-            config.timing_config.timing_budget_us,
-        )?;
-        set_inter_measurement_period_milli_seconds(
-            &mut device,
-            config.timing_config.inter_measurement_period_ms,
-        )?;
+        set_timing_config(&mut device, timing_config)?;
 
         info!("  Starting measurement...");
-        start_measurement(&mut device, &mut i2c)?;
-
-        info!("VL53L1X initialization complete");
-        Ok(Self {
-            device,
-            gpio_interrupt: config.gpio_interrupt,
-            i2c,
-            last_data: RangingMeasurementData::default(),
-            recovery_mode: false,
-            on_new_measurement: None,
-        })
+        start_measurement(&mut device, i2c)?;
+
+        Ok(device)
     }
 
     /// Attempt to recover from a sensor error by stopping and restarting measurements
-    async fn recover_sensor(&mut self) -> Result<(), Error<i2c::Error>> {
+    async fn recover_sensor(&mut self) -> Result<(), Error<I::Error>> {
         info!("  Attempting sensor recovery...");
+        self.bus.acquire().await;
         stop_measurement(&mut self.device, &mut self.i2c)?;
         Timer::after(Duration::from_millis(100)).await;
-        start_measurement(&mut self.device, &mut self.i2c)?;
+        self.bus.acquire().await;
+        self.start_ranging()?;
         info!("  Sensor recovered");
         Ok(())
     }
-}
 
-// Step 1: Implement the base Sensor trait
-impl Sensor<RangingMeasurementData, SpawnError> for VL53L1XSensor {
-    async fn start_continuous_measurement(
-        &'static mut self,
-        spawner: &mut Spawner,
-        callable: &'static dyn Fn(&RangingMeasurementData),
-    ) -> Result<(), SpawnError> {
-        self.on_new_measurement = Some(callable);
-        spawner.spawn(distance_sensor_task(self))
+    /// Power cycles the sensor and initializes it again, measuring if `state` requires it
+    async fn hard_reset(
+        &mut self,
+        state: RunState,
+    ) -> Result<(), BringUpError<I::Error, P::Error>> {
+        hard_reset(
+            &mut self.xshut_pin,
+            &mut self.bus,
+            &mut Delay,
+            ChipKind::VL53L1X,
+            self.address,
+        )
+        .await?;
+        self.i2c = RemappedI2c::new(self.bus.clone(), self.address);
+        self.bus.acquire().await;
+        self.device = Self::init_device(&self.timing_config, &self.ranging_config, &mut self.i2c)
+            .map_err(BringUpError::VL53L1X)?;
+        self.zone = 0;
+        if !state.is_measuring() {
+            stop_measurement(&mut self.device, &mut self.i2c).map_err(BringUpError::VL53L1X)?;
+        }
+        Ok(())
     }
 
-    fn get_latest_measurement(&self) -> &RangingMeasurementData {
-        &self.last_data
+    /// Starts ranging, in the first zone if the sensor scans
+    fn start_ranging(&mut self) -> Result<(), Error<I::Error>> {
+        self.zone = 0;
+        start_measurement(&mut self.device, &mut self.i2c)
     }
-}
 
-#[embassy_executor::task]
-async fn distance_sensor_task(self_: &'static mut VL53L1XSensor) -> ! {
-    debug!("Distance sensor task running");
-
-    loop {
-        if !self_.recovery_mode {
-            self_.gpio_interrupt.wait_for_falling_edge().await;
-        } else {
-            while let Err(e) =
-                wait_measurement_data_ready(&mut self_.device, &mut self_.i2c, &mut Delay)
-            {
-                let str = match e {
-                    nb::Error::Other(e) => format!("other error: {:?}", e),
-                    nb::Error::WouldBlock => String::from("Operation would block"),
-                };
-                warn!(
-                    "Waiting for measurement data ready failed ({}), retrying...",
-                    str.as_str()
-                );
-                Timer::after(Duration::from_millis(10)).await;
-            }
-            info!("Measurement data ready after recovery");
-            self_.recovery_mode = false;
+    /// Reads the result of the measurement that raised the interrupt, without blocking the executor.
+    /// The reading is returned as the chip reports it, without calibration nor filtering.
+    async fn read_ranging_data(&mut self) -> Result<DistanceReading, I::Error> {
+        let mut result = [0u8; 17];
+        AsyncI2c::write_read(
+            &mut self.bus,
+            self.address,
+            &RESULT_RANGE_STATUS.to_be_bytes(),
+            &mut result,
+        )
+        .await?;
+        let status = RANGE_STATUS_CODES
+            .get((result[0] & 0x1F) as usize)
+            .copied()
+            .unwrap_or(RANGE_STATUS_NONE);
+        // RESULT__SIGMA_SD0 is a 14.2 fixed point number
+        let sigma_mm = u16::from_be_bytes([result[9], result[10]]) as f32 / 4.0;
+        let distance_mm = u16::from_be_bytes([result[13], result[14]]);
+        // RESULT__PEAK_SIGNAL_COUNT_RATE_CROSSTALK_CORRECTED_MCPS_SD0 is a 9.7 fixed point number
+        let signal_rate_mcps = u16::from_be_bytes([result[15], result[16]]) as f32 / 128.0;
+        Ok(DistanceReading {
+            mm: distance_mm,
+            sigma_mm: Some(sigma_mm),
+            signal_rate_mcps,
+            quality: range_quality(status),
+            zone: self.ranging_config.scan.map(|_| self.zone as u8),
+        })
+    }
+
+    /// Clears the interrupt so that the next measurement can raise it, without blocking the executor
+    async fn clear_interrupt(&mut self) -> Result<(), I::Error> {
+        let [high, low] = SYSTEM_INTERRUPT_CLEAR.to_be_bytes();
+        AsyncI2c::write(&mut self.bus, self.address, &[high, low, 0x01]).await
+    }
+
+    /// Moves to the next zone if the sensor scans, then clears the interrupt. The ROI is written
+    /// while ranging, it is used from the next measurement on.
+    async fn next_measurement(&mut self) -> Result<(), I::Error> {
+        if let Some(scan) = self.ranging_config.scan {
+            let zone = (self.zone + 1) % scan.zones().len();
+            let [high, low] = ROI_CONFIG_USER_ROI_CENTRE_SPAD.to_be_bytes();
+            let [center, size] = roi_registers(&scan.zones()[zone]);
+            AsyncI2c::write(&mut self.bus, self.address, &[high, low, center, size]).await?;
+            self.zone = zone;
         }
+        self.clear_interrupt().await
+    }
 
-        // Get the ranging measurement data
-        match get_ranging_measurement_data(&mut self_.device, &mut self_.i2c) {
-            Err(e) => {
-                warn!("Error getting ranging data: {:?}", e);
-                if self_.recover_sensor().await.is_err() {
-                    error!("Failed to recover sensor, waiting before retry...");
-                    self_.recovery_mode = true;
-                    Timer::after(Duration::from_millis(500)).await;
+    /// Puts the device in the state required by `command`, returns the new state of the task.
+    /// The bus must have been acquired.
+    fn apply_command(
+        &mut self,
+        command: SensorCommand<ToFConfig>,
+        state: RunState,
+    ) -> Result<RunState, Error<I::Error>> {
+        let new_state = state.after(&command);
+        match command {
+            SensorCommand::Reconfigure(ToFConfig::Timing(timing_config)) => {
+                if state.is_measuring() {
+                    stop_measurement(&mut self.device, &mut self.i2c)?;
+                }
+                set_timing_config(&mut self.device, &timing_config)?;
+                self.timing_config = timing_config;
+                if new_state.is_measuring() {
+                    self.start_ranging()?;
                 }
-                continue;
             }
-            Ok(rmd) => {
-                if rmd.range_status != SIGNAL_FAIL {
-                    // debug!(
-                    //     "Distance: {} mm, Sigma: {} mm, Status: {:?}",
-                    //     rmd.range_milli_meter,
-                    //     rmd.sigma_milli_meter as f64 / 65536.0,
-                    //     rmd.range_status
-                    // );
-                    self_.last_data = rmd;
-                    self_.on_new_measurement.unwrap()(&self_.last_data);
-                    // if let Some(callback) = &self_.on_new_measurement {
-                    //     callback(&self_.last_data);
-                    // }
+            SensorCommand::Reconfigure(ToFConfig::Ranging(ranging_config)) => {
+                if state.is_measuring() {
+                    stop_measurement(&mut self.device, &mut self.i2c)?;
                 }
+                set_ranging_config(&mut self.device, &ranging_config)?;
+                self.filters = zone_filters(&self.filters[0], &ranging_config);
+                self.ranging_config = ranging_config;
+                if new_state.is_measuring() {
+                    self.start_ranging()?;
+                }
+            }
+            // Applied on the host, the device keeps measuring
+            SensorCommand::Reconfigure(ToFConfig::Calibration(calibration)) => {
+                self.calibration = calibration;
             }
+            _ if new_state.is_measuring() && !state.is_measuring() => {
+                self.start_ranging()?;
+            }
+            _ if !new_state.is_measuring() && state.is_measuring() => {
+                stop_measurement(&mut self.device, &mut self.i2c)?;
+            }
+            _ => {}
         }
+        Ok(new_state)
+    }
+}
 
-        // Clear interrupt and start next measurement
-        if let Err(e) =
-            clear_interrupt_and_start_measurement(&mut self_.device, &mut self_.i2c, &mut Delay)
-        {
-            warn!("Error clearing interrupt: {:?}", e);
-            if self_.recover_sensor().await.is_err() {
-                error!("Failed to recover sensor, waiting before retry...");
-                Timer::after(Duration::from_millis(500)).await;
-                self_.recovery_mode = true;
+impl<I: SharedBus, P: OutputPin, W: Wait> VL53L1XSensor<I, P, W>
+where
+    I::Error: Format,
+    P::Error: Format,
+{
+    /// Records an error and escalates according to the recovery policy of the sensor
+    async fn recover(&mut self, state: RunState) {
+        match self.recovery.failed() {
+            RecoveryAction::Retry => {}
+            RecoveryAction::Restart => {
+                self.handle.record(HealthEvent::Restart);
+                if let Err(e) = self.recover_sensor().await {
+                    warn!("VL53L1X restart failed: {:?}", e);
+                    self.handle.record(HealthEvent::Error(ErrorKind::Recovery));
+                }
+            }
+            RecoveryAction::HardReset => {
+                warn!("Resetting VL53L1X");
+                self.handle.record(HealthEvent::HardReset);
+                match self.hard_reset(state).await {
+                    Ok(()) => {
+                        info!("VL53L1X reset and initialized again");
+                        self.recovery.reset_done();
+                    }
+                    Err(e) => {
+                        error!("VL53L1X reset failed: {}", e);
+                        self.handle.record(HealthEvent::Error(ErrorKind::Recovery));
+                        Timer::after(self.recovery.policy().hard_reset_backoff).await;
+                    }
+                }
             }
         }
     }
 }
 
-impl MeasurementData<RangeStatus> for RangingMeasurementData {
-    fn get_distance_mm(&self) -> i16 {
-        self.range_milli_meter
+fn set_timing_config<E>(device: &mut Device, timing_config: &TimingConfig) -> Result<(), Error<E>> {
+    set_measurement_timing_budget_micro_seconds(device, timing_config.timing_budget_us)?;
+    set_inter_measurement_period_milli_seconds(device, timing_config.inter_measurement_period_ms)?;
+    Ok(())
+}
+
+/// Sets the distance mode and the ROI, the first zone when scanning. The distance mode keeps the
+/// timing budget, as long as it is supported by the new mode.
+fn set_ranging_config<E>(
+    device: &mut Device,
+    ranging_config: &RangingConfig,
+) -> Result<(), Error<E>> {
+    let distance_mode = match ranging_config.distance_mode {
+        DistanceMode::Short => vl53l1::DistanceMode::Short,
+        DistanceMode::Medium => vl53l1::DistanceMode::Medium,
+        DistanceMode::Long => vl53l1::DistanceMode::Long,
+    };
+    set_distance_mode(device, distance_mode)?;
+    let roi = match &ranging_config.scan {
+        Some(scan) => scan.zones()[0],
+        None => ranging_config.roi,
+    };
+    let (left, top, right, bottom) = roi.bounds();
+    set_user_roi(
+        device,
+        UserRoi {
+            top_left_x: left,
+            top_left_y: top,
+            bot_right_x: right,
+            bot_right_y: bottom,
+        },
+    )?;
+    Ok(())
+}
+
+/// Filter chains without history for each zone of `ranging_config`, built like `template`
+fn zone_filters(
+    template: &FilterChain,
+    ranging_config: &RangingConfig,
+) -> Vec<FilterChain, MAX_ZONES> {
+    let zones = ranging_config.scan.map_or(1, |scan| scan.zones().len());
+    (0..zones).map(|_| template.cleared()).collect()
+}
+
+/// Values of ROI_CONFIG__USER_ROI_CENTRE_SPAD and ROI_CONFIG__USER_ROI_REQUESTED_GLOBAL_XY_SIZE
+/// selecting `roi`, with the SPAD numbering of the ST ultra lite driver
+fn roi_registers(roi: &Roi) -> [u8; 2] {
+    let (left, top, right, bottom) = roi.bounds();
+    let (width, height) = (right - left + 1, top - bottom + 1);
+    // The centre SPAD is the one just after the middle for an even size
+    let (x, y) = (left + width / 2, bottom + height / 2);
+    let center = if y > 7 {
+        128 + (x << 3) + (15 - y)
+    } else {
+        ((15 - x) << 3) + y
+    };
+    [center, ((height - 1) << 4) | (width - 1)]
+}
+
+impl<I: SharedBus + 'static, P: OutputPin + 'static, W: Wait + 'static>
+    Sensor<DistanceReading, SpawnError, ToFConfig> for VL53L1XSensor<I, P, W>
+where
+    I::Error: Format,
+    P::Error: Format,
+    W::Error: Format,
+{
+    async fn start_continuous_measurement<S>(
+        &'static mut self,
+        spawner: &mut Spawner,
+        task: impl FnOnce(&'static mut Self) -> SpawnToken<S>,
+    ) -> Result<SensorHandle<DistanceReading, ToFConfig>, SpawnError> {
+        let handle = self.handle;
+        spawner.spawn(task(self))?;
+        Ok(handle)
+    }
+
+    fn handle(&self) -> SensorHandle<DistanceReading, ToFConfig> {
+        self.handle
     }
 
-    fn get_sigma_mm(&self) -> f64 {
-        self.sigma_milli_meter as f64 / 65536.0
+    async fn run(&mut self) -> Infallible {
+        debug!("Distance sensor task running");
+        let mut state = RunState::Continuous;
+
+        loop {
+            let timeout = self.recovery.interrupt_timeout(&self.timing_config);
+            let event =
+                next_event(self.handle, &mut self.gpio_interrupt, state, Some(timeout)).await;
+            let timestamp = match event {
+                Event::DataReady(timestamp) => timestamp,
+                Event::Timeout => {
+                    warn!("VL53L1X data-ready interrupt timed out");
+                    self.handle.record(HealthEvent::InterruptTimeout);
+                    self.recover(state).await;
+                    continue;
+                }
+                Event::Command(command) => {
+                    self.bus.acquire().await;
+                    match self.apply_command(command, state) {
+                        Ok(new_state) => state = new_state,
+                        Err(e) => {
+                            warn!("VL53L1X command {} failed: {:?}", command, e);
+                            self.handle.record(HealthEvent::Error(ErrorKind::Command));
+                        }
+                    }
+                    continue;
+                }
+            };
+
+            // Get the ranging measurement data
+            match self.read_ranging_data().await {
+                Err(e) => {
+                    warn!("Error getting ranging data: {:?}", e);
+                    self.handle.record(HealthEvent::Error(ErrorKind::Read));
+                    self.recover(state).await;
+                    continue;
+                }
+                Ok(reading) => {
+                    self.recovery.succeeded();
+                    // debug!("Reading: {}", reading);
+                    let filter = &mut self.filters[self.zone];
+                    let reading = filter.apply(self.calibration.correct(reading));
+                    let usable = reading.is_usable();
+                    self.handle.publish_with_quality(reading, timestamp, usable);
+                }
+            }
+
+            if state == RunState::SingleShot {
+                self.bus.acquire().await;
+                match stop_measurement(&mut self.device, &mut self.i2c) {
+                    Ok(()) => state = RunState::Stopped,
+                    Err(e) => warn!("Error stopping after single shot: {:?}", e),
+                }
+                continue;
+            }
+
+            // Clear interrupt and start next measurement
+            if let Err(e) = self.next_measurement().await {
+                warn!("Error clearing interrupt: {:?}", e);
+                self.handle.record(HealthEvent::Error(ErrorKind::Read));
+                self.recover(state).await;
+            }
+        }
     }
+}
+
+/// First register of the result block, up to RESULT__FINAL_CROSSTALK_CORRECTED_RANGE_MM_SD0
+const RESULT_RANGE_STATUS: u16 = 0x0089;
+const SYSTEM_INTERRUPT_CLEAR: u16 = 0x0086;
+/// Followed by ROI_CONFIG__USER_ROI_REQUESTED_GLOBAL_XY_SIZE
+const ROI_CONFIG_USER_ROI_CENTRE_SPAD: u16 = 0x007F;
+
+const RANGE_STATUS_NONE: u8 = 255;
+
+/// Maps the device range status to the range status of the ST API, as the ST ultra lite driver does
+const RANGE_STATUS_CODES: [u8; 24] = [
+    255, 255, 255, 5, 2, 4, 1, 7, 3, 0, 255, 255, 9, 13, 255, 255, 255, 255, 10, 6, 255, 255, 11,
+    12,
+];
 
-    fn get_status(&self) -> RangeStatus {
-        self.range_status
+/// Quality of a measurement from its range status as numbered by the ST API
+fn range_quality(status: u8) -> RangeQuality {
+    match status {
+        // RANGE_VALID
+        0 => RangeQuality::Valid,
+        // SIGMA_FAIL, RANGE_VALID_MIN_RANGE_CLIPPED, RANGE_VALID_NO_WRAP_CHECK_FAIL,
+        // RANGE_VALID_MERGED_PULSE
+        1 | 3 | 6 | 11 => RangeQuality::Degraded,
+        // SIGNAL_FAIL, OUTOFBOUNDS_FAIL, TARGET_PRESENT_LACK_OF_SIGNAL
+        2 | 4 | 12 => RangeQuality::NoTarget,
+        _ => RangeQuality::Invalid,
     }
 }
@@ -0,0 +1,75 @@
+use crate::sensor::channel::Timestamped;
+use crate::sensor::distance::{DistanceReading, RangeQuality, SensorPosition};
+use crate::sensor::frame::{FrameField, FrameHandle};
+use crate::sensor::geometry::{SensorMount, mount};
+use core::cell::RefCell;
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::WaitResult;
+use micromouse::wall_detector::WallDetector;
+pub use micromouse::wall_detector::{CellThresholds, Observation, Walls};
+
+/// Observation of the sensor mounted as `mount`, from its field in a sensor frame
+pub fn observe(mount: Option<&SensorMount>, field: &FrameField<DistanceReading>) -> Observation {
+    match (mount, field.fresh_measurement()) {
+        (Some(_), Some(reading)) if reading.quality == RangeQuality::NoTarget => {
+            Observation::NoTarget
+        }
+        (Some(mount), Some(reading)) => mount
+            .wall_from_reading(reading)
+            .map_or(Observation::Unknown, |wall| {
+                Observation::Distance(wall.distance_mm)
+            }),
+        _ => Observation::Unknown,
+    }
+}
+
+/// Latest walls classified by [`wall_detection_task`]
+static WALLS: Mutex<CriticalSectionRawMutex, RefCell<Option<Timestamped<Walls>>>> =
+    Mutex::new(RefCell::new(None));
+
+/// Returns the walls around the robot in the latest sensor frame, `None` before the first one
+pub fn walls() -> Option<Timestamped<Walls>> {
+    WALLS.lock(|walls| *walls.borrow())
+}
+
+/// Classifies the walls around the robot in every sensor frame, see [`walls`]
+#[embassy_executor::task]
+pub async fn wall_detection_task(frames: FrameHandle, thresholds: CellThresholds) -> ! {
+    let mut subscription = frames.subscribe().unwrap();
+    let mut detector = WallDetector::new(thresholds);
+    let (left, front, right) = (
+        mount(SensorPosition::Left),
+        mount(SensorPosition::Front),
+        mount(SensorPosition::Right),
+    );
+    let mut previous: Option<Walls> = None;
+    loop {
+        let frame = match subscription.next().await {
+            WaitResult::Message(frame) => frame,
+            WaitResult::Lagged(count) => {
+                warn!("Wall detection lagged, {} frames missed", count);
+                continue;
+            }
+        };
+        let walls = detector.update(
+            observe(left, frame.measurement.distance(SensorPosition::Left)),
+            observe(front, frame.measurement.distance(SensorPosition::Front)),
+            observe(right, frame.measurement.distance(SensorPosition::Right)),
+        );
+        if previous.is_none_or(|previous| !previous.same_presence(&walls)) {
+            info!(
+                "Walls: left {}, front {}, right {}",
+                walls.left.present, walls.front.present, walls.right.present
+            );
+        }
+        previous = Some(walls);
+        WALLS.lock(|latest| {
+            *latest.borrow_mut() = Some(Timestamped {
+                measurement: walls,
+                timestamp: frame.timestamp,
+            })
+        });
+    }
+}
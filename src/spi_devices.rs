@@ -0,0 +1,187 @@
+use crate::Irqs;
+use crate::sensor::health::{SensorId, monitor};
+use crate::sensor::mpu9250::{ImuError, ImuHandle, Mpu9250Config, Mpu9250Sensor, register_imu};
+use crate::sensor::{sensor_task, start_on_heap};
+use core::convert::Infallible;
+use core::sync::atomic::{AtomicU32, Ordering};
+use defmt::{Format, info, warn};
+use embassy_executor::{SpawnError, Spawner};
+use embassy_stm32::Peri;
+use embassy_stm32::exti::ExtiInput;
+use embassy_stm32::gpio::{Level, Output, Pull, Speed};
+use embassy_stm32::mode::Async;
+use embassy_stm32::peripherals::{DMA2_CH2, DMA2_CH3, EXTI2, PA2, PB3, PB4, PB5, PC6, SPI1};
+use embassy_stm32::spi;
+use embassy_stm32::spi::{Config, Spi};
+use embassy_stm32::time::Hertz;
+use embassy_time::{Duration, Timer};
+use embedded_hal::spi::{ErrorType, SpiBus};
+use embedded_hal_async::spi::SpiBus as AsyncSpiBus;
+
+/// Clock used to initialize the MPU9250, slow for maximum reliability
+const INIT_FREQUENCY: Hertz = Hertz::khz(100);
+/// Clock used once the MPU9250 is initialized, the fastest at which all its registers can be
+/// accessed
+const RUN_FREQUENCY: Hertz = Hertz::mhz(1);
+
+/// Frequency [`Spi1Bus`] switches to before its next transfer
+static SPI1_FREQUENCY: AtomicU32 = AtomicU32::new(INIT_FREQUENCY.0);
+
+sensor_task!(mpu9250_task, Mpu9250Sensor<Spi1Bus, Output<'static>>);
+
+/// SPI1, whose clock can be changed after it is handed to the IMU driver with
+/// [`set_spi1_frequency`]
+pub struct Spi1Bus {
+    spi: Spi<'static, Async, spi::mode::Master>,
+    config: Config,
+}
+
+impl Spi1Bus {
+    /// Applies the frequency requested with [`set_spi1_frequency`] if it changed
+    fn update_frequency(&mut self) {
+        let frequency = Hertz(SPI1_FREQUENCY.load(Ordering::Relaxed));
+        if frequency != self.config.frequency {
+            self.config.frequency = frequency;
+            if self.spi.set_config(&self.config).is_err() {
+                warn!("Unsupported SPI1 frequency: {} Hz", frequency.0);
+            }
+        }
+    }
+}
+
+impl ErrorType for Spi1Bus {
+    type Error = spi::Error;
+}
+
+impl SpiBus for Spi1Bus {
+    fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.blocking_read(words)
+    }
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.blocking_write(words)
+    }
+
+    fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.blocking_transfer(read, write)
+    }
+
+    fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.blocking_transfer_in_place(words)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        SpiBus::flush(&mut self.spi)
+    }
+}
+
+/// Transfers over DMA, used to read the FIFO of the IMU
+impl AsyncSpiBus for Spi1Bus {
+    async fn read(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.read(words).await
+    }
+
+    async fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.write(words).await
+    }
+
+    async fn transfer(&mut self, read: &mut [u8], write: &[u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.transfer(read, write).await
+    }
+
+    async fn transfer_in_place(&mut self, words: &mut [u8]) -> Result<(), Self::Error> {
+        self.update_frequency();
+        self.spi.transfer_in_place(words).await
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        // The transfers are complete once awaited
+        Ok(())
+    }
+}
+
+/// Changes the clock of SPI1, from its next transfer on
+pub fn set_spi1_frequency(frequency: Hertz) {
+    SPI1_FREQUENCY.store(frequency.0, Ordering::Relaxed);
+}
+
+/// Why the IMU could not be started
+#[derive(Debug, Format)]
+pub enum SpiInitError {
+    /// The MPU9250 did not answer or could not be configured
+    Imu(ImuError<spi::Error, Infallible>),
+    Spawn(SpawnError),
+}
+
+/// Initializes the MPU9250 on SPI1 and starts its measurements.
+///
+/// The IMU is registered, see [`crate::sensor::mpu9250::imu`], and its health is monitored. It
+/// publishes nothing until its gyroscope is calibrated, the robot must stay still meanwhile.
+pub async fn init_spi_devices(
+    spawner: &mut Spawner,
+    spi_peri: Peri<'static, SPI1>,
+    sck: Peri<'static, PB3>,
+    mosi: Peri<'static, PB5>,
+    miso: Peri<'static, PB4>,
+    tx_dma: Peri<'static, DMA2_CH3>,
+    rx_dma: Peri<'static, DMA2_CH2>,
+    cs: Peri<'static, PC6>,
+    interrupt_pin: Peri<'static, PA2>,
+    interrupt_channel: Peri<'static, EXTI2>,
+    irqs: Irqs,
+    config: Mpu9250Config,
+) -> Result<ImuHandle, SpiInitError> {
+    let spi_config = spi_config();
+    set_spi1_frequency(spi_config.frequency);
+    let spi = Spi::new(spi_peri, sck, mosi, miso, tx_dma, rx_dma, spi_config);
+    let spi = Spi1Bus {
+        spi,
+        config: spi_config,
+    };
+
+    let mut chip_select = Output::new(cs, Level::High, Speed::Medium);
+    let interrupt = ExtiInput::new(interrupt_pin, interrupt_channel, Pull::None, irqs);
+    pulse_chip_select(&mut chip_select).await;
+
+    info!("Initializing MPU9250 IMU...");
+    let imu =
+        Mpu9250Sensor::init_new(spi, chip_select, interrupt, config).map_err(SpiInitError::Imu)?;
+    set_spi1_frequency(RUN_FREQUENCY);
+
+    let handle = start_on_heap(spawner, imu, mpu9250_task)
+        .await
+        .map_err(|(e, _)| SpiInitError::Spawn(e))?;
+    register_imu(handle);
+    monitor(SensorId::Imu, handle.health());
+    Ok(handle)
+}
+
+fn spi_config() -> Config {
+    let mut spi_config = Config::default();
+    spi_config.frequency = INIT_FREQUENCY;
+    // MPU9250 library requires Mode 3 (CPOL=1, CPHA=1)
+    // This matches mpu9250::MODE constant: IdleHigh, CaptureOnSecondTransition
+    spi_config.mode = spi::Mode {
+        polarity: spi::Polarity::IdleHigh,
+        phase: spi::Phase::CaptureOnSecondTransition,
+    };
+    spi_config
+}
+
+/// The MPU9250 requires CS to be high during power-on to enable SPI mode.
+/// Pulse CS to ensure the chip recognizes SPI mode.
+async fn pulse_chip_select(chip_select: &mut Output<'static>) {
+    chip_select.set_high();
+    Timer::after(Duration::from_millis(50)).await;
+    chip_select.set_low();
+    Timer::after(Duration::from_micros(100)).await;
+    chip_select.set_high();
+    Timer::after(Duration::from_millis(10)).await;
+}
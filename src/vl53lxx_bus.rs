@@ -0,0 +1,306 @@
+use crate::reading::{DistanceReading, RangeQuality};
+use defmt::Format;
+use embassy_time::Duration;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::i2c::{ErrorType, I2c, Operation};
+use embedded_hal_async::delay::DelayNs;
+use embedded_hal_async::i2c::I2c as AsyncI2c;
+
+/// I2C address of every VL53LXX after a reset
+pub const DEFAULT_ADDRESS: u8 = 0x29;
+
+/// Time for a VL53LXX to boot after its XSHUT pin goes high (1.2ms max in both datasheets)
+pub const BOOT_TIME: Duration = Duration::from_millis(10);
+/// Time XSHUT is held low to reset a sensor
+pub const RESET_TIME: Duration = Duration::from_millis(10);
+
+/// Register holding the device range status of the VL53L0X, followed by the result of the last
+/// measurement
+const RESULT_RANGE_STATUS: u8 = 0x14;
+const SYSTEM_INTERRUPT_CLEAR: u8 = 0x0B;
+
+/// Which ToF chip is mounted at a position
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Format)]
+pub enum ChipKind {
+    VL53L0X,
+    VL53L1X,
+}
+
+/// Why a sensor could not be powered up or reset
+#[derive(Debug, PartialEq, Eq, Format)]
+pub enum PowerError<E, P> {
+    /// The XSHUT pin could not be driven
+    Xshut(P),
+    /// The sensor didn't acknowledge its new address, it is probably not connected
+    Address(E),
+}
+
+/// Makes a freshly powered sensor answer at `address` instead of [`DEFAULT_ADDRESS`].
+///
+/// Only the sensor whose XSHUT pin is high must be listening on the bus when this is called.
+pub async fn assign_address<I: AsyncI2c>(
+    i2c: &mut I,
+    chip: ChipKind,
+    address: u8,
+) -> Result<(), I::Error> {
+    if address == DEFAULT_ADDRESS {
+        return Ok(());
+    }
+    match chip {
+        // I2C_SLAVE_DEVICE_ADDRESS, 8-bit register index
+        ChipKind::VL53L0X => i2c.write(DEFAULT_ADDRESS, &[0x8A, address & 0x7F]).await,
+        // I2C_SLAVE__DEVICE_ADDRESS, 16-bit register index
+        ChipKind::VL53L1X => {
+            i2c.write(DEFAULT_ADDRESS, &[0x00, 0x01, address & 0x7F])
+                .await
+        }
+    }
+}
+
+/// Releases a sensor from reset, waits for it to boot and moves it to `address`
+pub async fn power_up<I: AsyncI2c, P: OutputPin>(
+    xshut_pin: &mut P,
+    i2c: &mut I,
+    delay: &mut impl DelayNs,
+    chip: ChipKind,
+    address: u8,
+) -> Result<(), PowerError<I::Error, P::Error>> {
+    xshut_pin.set_high().map_err(PowerError::Xshut)?;
+    delay.delay_us(BOOT_TIME.as_micros() as u32).await;
+    assign_address(i2c, chip, address)
+        .await
+        .map_err(PowerError::Address)
+}
+
+/// Power cycles a sensor through its XSHUT pin and moves it back to `address`.
+///
+/// The other sensors of the bus keep their address, so only this one answers at the default
+/// address while it is being moved.
+pub async fn hard_reset<I: AsyncI2c, P: OutputPin>(
+    xshut_pin: &mut P,
+    i2c: &mut I,
+    delay: &mut impl DelayNs,
+    chip: ChipKind,
+    address: u8,
+) -> Result<(), PowerError<I::Error, P::Error>> {
+    xshut_pin.set_low().map_err(PowerError::Xshut)?;
+    delay.delay_us(RESET_TIME.as_micros() as u32).await;
+    power_up(xshut_pin, i2c, delay, chip, address).await
+}
+
+/// Reads the measurement that raised the data-ready interrupt of the VL53L0X at `address` and
+/// clears the interrupt. The reading is returned as the chip reports it, without calibration nor
+/// filtering.
+pub async fn read_vl53l0x_range<I: AsyncI2c>(
+    i2c: &mut I,
+    address: u8,
+) -> Result<DistanceReading, I::Error> {
+    let mut result = [0u8; 12];
+    i2c.write_read(address, &[RESULT_RANGE_STATUS], &mut result)
+        .await?;
+    i2c.write(address, &[SYSTEM_INTERRUPT_CLEAR, 0x01]).await?;
+    // RESULT_PEAK_SIGNAL_RATE_REF is a 9.7 fixed point number
+    let signal_rate_mcps = u16::from_be_bytes([result[6], result[7]]) as f32 / 128.0;
+    let distance_mm = u16::from_be_bytes([result[10], result[11]]);
+    Ok(DistanceReading {
+        mm: distance_mm,
+        sigma_mm: None,
+        signal_rate_mcps,
+        quality: vl53l0x_quality(result[0]),
+        zone: None,
+    })
+}
+
+/// Decodes the device range status of `RESULT_RANGE_STATUS` the way the ST API does, without
+/// the sigma and signal checks it computes on the host
+fn vl53l0x_quality(result_range_status: u8) -> RangeQuality {
+    match (result_range_status & 0x78) >> 3 {
+        // Range valid
+        11 => RangeQuality::Valid,
+        // Min range fail
+        8 | 10 => RangeQuality::Degraded,
+        // Signal fail
+        4 => RangeQuality::NoTarget,
+        // Hardware fail, phase fail, and 0, 5, 7 and 12 to 15: no range completed
+        _ => RangeQuality::Invalid,
+    }
+}
+
+/// I2C device redirecting the transactions made to [`DEFAULT_ADDRESS`] to another address.
+///
+/// The driver crates always talk to the default address, this lets them drive a sensor that was
+/// moved with [`assign_address`].
+pub struct RemappedI2c<I> {
+    inner: I,
+    address: u8,
+}
+
+impl<I> RemappedI2c<I> {
+    pub fn new(inner: I, address: u8) -> Self {
+        Self { inner, address }
+    }
+
+    fn map(&self, address: u8) -> u8 {
+        if address == DEFAULT_ADDRESS {
+            self.address
+        } else {
+            address
+        }
+    }
+}
+
+impl<I: ErrorType> ErrorType for RemappedI2c<I> {
+    type Error = I::Error;
+}
+
+impl<I: I2c> I2c for RemappedI2c<I> {
+    fn read(&mut self, address: u8, read: &mut [u8]) -> Result<(), Self::Error> {
+        self.inner.read(self.map(address), read)
+    }
+
+    fn write(&mut self, address: u8, write: &[u8]) -> Result<(), Self::Error> {
+        self.inner.write(self.map(address), write)
+    }
+
+    fn write_read(
+        &mut self,
+        address: u8,
+        write: &[u8],
+        read: &mut [u8],
+    ) -> Result<(), Self::Error> {
+        self.inner.write_read(self.map(address), write, read)
+    }
+
+    fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        self.inner.transaction(self.map(address), operations)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+    use alloc::vec::Vec;
+    use embassy_futures::block_on;
+    use embedded_hal::i2c::{ErrorKind, NoAcknowledgeSource};
+    use embedded_hal_mock::eh1::delay::{CheckedDelay, Transaction as DelayTransaction};
+    use embedded_hal_mock::eh1::digital::{Mock as PinMock, State, Transaction as PinTransaction};
+    use embedded_hal_mock::eh1::i2c::{Mock as I2cMock, Transaction as I2cTransaction};
+
+    const ADDRESS: u8 = 0x30;
+
+    /// The result registers of a VL53L0X that measured 150 mm with a signal rate of 12.5 MCPS
+    fn result_bytes(range_status: u8) -> Vec<u8> {
+        let mut result = vec![range_status << 3, 0, 0, 0, 0, 0];
+        result.extend(1600u16.to_be_bytes());
+        result.extend([0, 0]);
+        result.extend(150u16.to_be_bytes());
+        result
+    }
+
+    #[test]
+    fn powers_up_and_moves_a_vl53l0x() {
+        let mut xshut = PinMock::new(&[PinTransaction::set(State::High)]);
+        let mut i2c = I2cMock::new(&[I2cTransaction::write(DEFAULT_ADDRESS, vec![0x8A, ADDRESS])]);
+        let mut delay = CheckedDelay::new(&[DelayTransaction::delay_ms(10)]);
+
+        let result = block_on(power_up(
+            &mut xshut,
+            &mut i2c,
+            &mut delay,
+            ChipKind::VL53L0X,
+            ADDRESS,
+        ));
+
+        assert_eq!(result, Ok(()));
+        xshut.done();
+        i2c.done();
+        delay.done();
+    }
+
+    #[test]
+    fn resets_and_moves_back_a_vl53l1x() {
+        let mut xshut = PinMock::new(&[
+            PinTransaction::set(State::Low),
+            PinTransaction::set(State::High),
+        ]);
+        let mut i2c = I2cMock::new(&[I2cTransaction::write(
+            DEFAULT_ADDRESS,
+            vec![0x00, 0x01, ADDRESS],
+        )]);
+        let mut delay = CheckedDelay::new(&[
+            DelayTransaction::delay_ms(10),
+            DelayTransaction::delay_ms(10),
+        ]);
+
+        let result = block_on(hard_reset(
+            &mut xshut,
+            &mut i2c,
+            &mut delay,
+            ChipKind::VL53L1X,
+            ADDRESS,
+        ));
+
+        assert_eq!(result, Ok(()));
+        xshut.done();
+        i2c.done();
+        delay.done();
+    }
+
+    #[test]
+    fn reports_a_sensor_that_does_not_take_its_address() {
+        let nack = ErrorKind::NoAcknowledge(NoAcknowledgeSource::Address);
+        let mut xshut = PinMock::new(&[PinTransaction::set(State::High)]);
+        let mut i2c = I2cMock::new(&[
+            I2cTransaction::write(DEFAULT_ADDRESS, vec![0x8A, ADDRESS]).with_error(nack)
+        ]);
+        let mut delay = CheckedDelay::new(&[DelayTransaction::delay_ms(10)]);
+
+        let result = block_on(power_up(
+            &mut xshut,
+            &mut i2c,
+            &mut delay,
+            ChipKind::VL53L0X,
+            ADDRESS,
+        ));
+
+        assert_eq!(result, Err(PowerError::Address(nack)));
+        xshut.done();
+        i2c.done();
+        delay.done();
+    }
+
+    #[test]
+    fn reads_a_vl53l0x_measurement_and_clears_its_interrupt() {
+        let mut i2c = I2cMock::new(&[
+            I2cTransaction::write_read(ADDRESS, vec![RESULT_RANGE_STATUS], result_bytes(11)),
+            I2cTransaction::write(ADDRESS, vec![SYSTEM_INTERRUPT_CLEAR, 0x01]),
+        ]);
+
+        let reading = block_on(read_vl53l0x_range(&mut i2c, ADDRESS)).unwrap();
+
+        assert_eq!(reading.mm, 150);
+        assert_eq!(reading.signal_rate_mcps, 12.5);
+        assert_eq!(reading.quality, RangeQuality::Valid);
+        assert_eq!(reading.sigma_mm, None);
+        i2c.done();
+    }
+
+    #[test]
+    fn reports_a_weak_signal_as_no_target() {
+        let mut i2c = I2cMock::new(&[
+            I2cTransaction::write_read(ADDRESS, vec![RESULT_RANGE_STATUS], result_bytes(4)),
+            I2cTransaction::write(ADDRESS, vec![SYSTEM_INTERRUPT_CLEAR, 0x01]),
+        ]);
+
+        let reading = block_on(read_vl53l0x_range(&mut i2c, ADDRESS)).unwrap();
+
+        assert_eq!(reading.quality, RangeQuality::NoTarget);
+        assert!(!reading.is_usable());
+        i2c.done();
+    }
+}
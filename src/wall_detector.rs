@@ -0,0 +1,261 @@
+use defmt::Format;
+
+/// Side of a maze cell
+pub const CELL_SIZE_MM: f32 = 180.0;
+pub const WALL_THICKNESS_MM: f32 = 12.0;
+/// Distance between the centre of a cell and its walls
+pub const CELL_WALL_DISTANCE_MM: f32 = (CELL_SIZE_MM - WALL_THICKNESS_MM) / 2.0;
+
+/// What a sensor tells about a wall, in the robot frame
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub enum Observation {
+    /// A wall at this distance from the centre of the robot
+    Distance(f32),
+    /// Nothing reflected the light back, there is no wall in range
+    NoTarget,
+    /// No fresh usable reading, nothing can be said
+    Unknown,
+}
+
+/// Distances deciding whether a wall is there, with a hysteresis band between them
+#[derive(Debug, Clone, Copy, Format)]
+pub struct WallThresholds {
+    /// A wall closer than this is present
+    pub present_below_mm: f32,
+    /// A wall further than this is absent. Between the two, the previous decision is kept.
+    pub absent_above_mm: f32,
+}
+
+impl WallThresholds {
+    /// Thresholds for a wall measured at `wall_mm` with the robot in the centre of the cell.
+    ///
+    /// The wall is present up to a quarter of a cell further, and absent from half a cell further,
+    /// far from the wall of the next cell.
+    pub fn around(wall_mm: f32) -> Self {
+        Self {
+            present_below_mm: wall_mm + CELL_SIZE_MM / 4.0,
+            absent_above_mm: wall_mm + CELL_SIZE_MM / 2.0,
+        }
+    }
+}
+
+/// Thresholds of the walls of the cell the robot is in, relative to its centre
+#[derive(Debug, Clone, Copy, Format)]
+pub struct CellThresholds {
+    pub left: WallThresholds,
+    pub front: WallThresholds,
+    pub right: WallThresholds,
+}
+
+impl CellThresholds {
+    /// Thresholds calibrated from the distances measured with the robot in the centre of a cell
+    /// closed on three sides
+    pub fn from_measured(left_mm: f32, front_mm: f32, right_mm: f32) -> Self {
+        Self {
+            left: WallThresholds::around(left_mm),
+            front: WallThresholds::around(front_mm),
+            right: WallThresholds::around(right_mm),
+        }
+    }
+}
+
+impl Default for CellThresholds {
+    /// Thresholds of a perfect maze
+    fn default() -> Self {
+        Self::from_measured(
+            CELL_WALL_DISTANCE_MM,
+            CELL_WALL_DISTANCE_MM,
+            CELL_WALL_DISTANCE_MM,
+        )
+    }
+}
+
+/// Decision about one wall
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct WallState {
+    pub present: bool,
+    /// From 0 to 1: 1 when the distance is outside of the hysteresis band on the side of the
+    /// decision, 0.5 in the middle of the band, 0 when nothing is known
+    pub confidence: f32,
+}
+
+impl WallState {
+    const UNKNOWN: WallState = WallState {
+        present: false,
+        confidence: 0.0,
+    };
+
+    /// Next decision from the previous one and a new observation
+    fn update(self, observation: Observation, thresholds: &WallThresholds) -> WallState {
+        let distance = match observation {
+            Observation::Distance(distance) => distance,
+            Observation::NoTarget => f32::INFINITY,
+            Observation::Unknown => {
+                return WallState {
+                    confidence: 0.0,
+                    ..self
+                };
+            }
+        };
+        let present = if distance < thresholds.present_below_mm {
+            true
+        } else if distance > thresholds.absent_above_mm {
+            false
+        } else {
+            self.present
+        };
+        let middle = (thresholds.present_below_mm + thresholds.absent_above_mm) / 2.0;
+        let width = (thresholds.absent_above_mm - thresholds.present_below_mm).max(1.0);
+        let margin = if present {
+            middle - distance
+        } else {
+            distance - middle
+        };
+        WallState {
+            present,
+            confidence: (0.5 + margin / width).clamp(0.0, 1.0),
+        }
+    }
+}
+
+/// Walls around the robot
+#[derive(Debug, Clone, Copy, PartialEq, Format)]
+pub struct Walls {
+    pub left: WallState,
+    pub front: WallState,
+    pub right: WallState,
+}
+
+impl Walls {
+    /// Whether the presence of every wall is the same in both, whatever the confidence
+    pub fn same_presence(&self, other: &Walls) -> bool {
+        self.left.present == other.left.present
+            && self.front.present == other.front.present
+            && self.right.present == other.right.present
+    }
+}
+
+/// Classifies the walls around the robot from the observations of the left, front and right
+/// sensors
+pub struct WallDetector {
+    thresholds: CellThresholds,
+    walls: Walls,
+}
+
+impl WallDetector {
+    pub fn new(thresholds: CellThresholds) -> Self {
+        Self {
+            thresholds,
+            walls: Walls {
+                left: WallState::UNKNOWN,
+                front: WallState::UNKNOWN,
+                right: WallState::UNKNOWN,
+            },
+        }
+    }
+
+    pub fn update(&mut self, left: Observation, front: Observation, right: Observation) -> Walls {
+        self.walls = Walls {
+            left: self.walls.left.update(left, &self.thresholds.left),
+            front: self.walls.front.update(front, &self.thresholds.front),
+            right: self.walls.right.update(right, &self.thresholds.right),
+        };
+        self.walls
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    /// Left wall seen by the robot driving along it past an opening, one observation per frame
+    const PAST_OPENING: [Observation; 12] = [
+        Observation::Distance(86.0),
+        Observation::Distance(85.0),
+        Observation::Distance(90.0),
+        Observation::Distance(150.0),
+        Observation::Distance(240.0),
+        Observation::NoTarget,
+        Observation::NoTarget,
+        Observation::Distance(160.0),
+        Observation::Distance(140.0),
+        Observation::Distance(110.0),
+        Observation::Distance(87.0),
+        Observation::Distance(84.0),
+    ];
+
+    fn left_walls(observations: &[Observation]) -> Vec<WallState> {
+        let mut detector = WallDetector::new(CellThresholds::default());
+        observations
+            .iter()
+            .map(|left| {
+                detector
+                    .update(*left, Observation::Unknown, Observation::Unknown)
+                    .left
+            })
+            .collect()
+    }
+
+    #[test]
+    fn default_thresholds_are_around_the_centre_of_the_cell() {
+        let thresholds = CellThresholds::default().front;
+        assert_eq!(CELL_WALL_DISTANCE_MM, 84.0);
+        assert_eq!(thresholds.present_below_mm, 129.0);
+        assert_eq!(thresholds.absent_above_mm, 174.0);
+    }
+
+    #[test]
+    fn opening_is_detected_with_hysteresis() {
+        let present: Vec<bool> = left_walls(&PAST_OPENING)
+            .iter()
+            .map(|wall| wall.present)
+            .collect();
+        assert_eq!(
+            present,
+            [
+                true, true, true, true, false, false, false, false, false, true, true, true
+            ]
+        );
+    }
+
+    #[test]
+    fn confidence_drops_in_the_hysteresis_band() {
+        let walls = left_walls(&PAST_OPENING);
+        assert_eq!(walls[0].confidence, 1.0);
+        assert!((0.5..1.0).contains(&walls[3].confidence));
+        assert_eq!(walls[5].confidence, 1.0);
+        assert!((0.5..1.0).contains(&walls[7].confidence));
+    }
+
+    #[test]
+    fn unknown_keeps_the_decision_without_confidence() {
+        let walls = left_walls(&[Observation::Distance(85.0), Observation::Unknown]);
+        assert_eq!(
+            walls[1],
+            WallState {
+                present: true,
+                confidence: 0.0
+            }
+        );
+        let walls = left_walls(&[Observation::Unknown]);
+        assert_eq!(walls[0], WallState::UNKNOWN);
+    }
+
+    #[test]
+    fn same_presence_ignores_the_confidence() {
+        let mut detector = WallDetector::new(CellThresholds::default());
+        let close = detector.update(
+            Observation::Distance(84.0),
+            Observation::NoTarget,
+            Observation::Distance(84.0),
+        );
+        let further = detector.update(
+            Observation::Distance(140.0),
+            Observation::NoTarget,
+            Observation::Distance(84.0),
+        );
+        assert_ne!(close, further);
+        assert!(close.same_presence(&further));
+    }
+}